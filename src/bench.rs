@@ -0,0 +1,142 @@
+use checker;
+use descriptor::SudiceExpression;
+use interpreter::Interpreter;
+use optimizer;
+use parser::Rdp;
+
+use pest::prelude::*;
+use rand;
+
+use std::fmt;
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+
+// Expressions exercising each of the interpreter's paths: rolls that are
+// only summed, rolls whose dice are kept for drops and rerolls, rolls of
+// a rolled number of dice, repeated subexpressions and wide selects.
+pub const EXPRESSIONS: &[&str] = &[
+    "3d6",
+    "100d6 + 1d20",
+    "4d6\\l1",
+    "10d10\\h3\\l3",
+    "8d6rl2",
+    "(1d4)d6",
+    "((1d4)d6)d4",
+    "1d20b2",
+    "(4d6\\l1)w3",
+    "[1d12 ? 1d4 1d6 1d8 1d10 1d12 2d6 2d8 3d4 3d6 4d4 4d6 : 1d20]",
+];
+
+// Samples per second may fall by this fraction against a baseline before
+// it is reported as a regression.
+pub const TOLERANCE: f64 = 0.10;
+
+const BATCH: usize = 1000;
+
+pub struct BenchResult {
+    pub expr: String,
+    pub parse_ns: f64,
+    pub check_ns: f64,
+    pub samples_per_sec: f64,
+}
+
+// Runs `f` in batches until at least `duration` has passed, returning the
+// number of runs per second. At least one batch is run, however short the
+// duration.
+fn rate<F: FnMut()>(duration: Duration, mut f: F) -> f64 {
+    let mut runs = 0;
+    let start = Instant::now();
+    loop {
+        for _ in 0..BATCH {
+            f();
+        }
+        runs += BATCH;
+        if start.elapsed() >= duration {
+            break;
+        }
+    }
+    runs as f64 / start.elapsed().as_secs_f64()
+}
+
+fn parse(expr: &str) -> SudiceExpression {
+    let mut parser = Rdp::new(StringInput::new(expr));
+    assert!(parser.expr() && parser.end(), "benchmark expression {} should parse", expr);
+    parser.compile().expect("benchmark expression should compile")
+}
+
+pub fn samples_per_second(code: &SudiceExpression, duration: Duration) -> f64 {
+    let mut rng = rand::thread_rng();
+    let mut interpreter = Interpreter::new(code);
+    rate(duration, || { let _ = interpreter.sample(&mut rng); })
+}
+
+// Times parsing, checking and sampling an expression, spending a tenth of
+// `duration` on each of the first two and the rest on sampling.
+pub fn run(expr: &str, duration: Duration) -> BenchResult {
+    let parse_ns = 1e9 / rate(duration / 10, || { let _ = parse(expr); });
+    let code = parse(expr);
+    let check_ns = 1e9 / rate(duration / 10, || {
+        checker::semantic_check(&code).expect("benchmark expression should check");
+    });
+    let samples_per_sec = samples_per_second(&optimizer::optimize(&code), duration * 8 / 10);
+    BenchResult { expr: expr.to_string(), parse_ns, check_ns, samples_per_sec }
+}
+
+// Reports are tab-separated, one expression per line after a header, so
+// they can be saved and compared against later runs.
+pub struct Report(pub Vec<BenchResult>);
+
+const HEADER: &str = "expr\tparse_ns\tcheck_ns\tsamples_per_sec";
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        for r in &self.0 {
+            writeln!(f, "{}\t{:.0}\t{:.0}\t{:.0}", r.expr, r.parse_ns, r.check_ns, r.samples_per_sec)?;
+        }
+        Ok(())
+    }
+}
+
+impl Report {
+    pub fn parse(text: &str) -> Result<Report, String> {
+        let mut lines = text.lines();
+        if lines.next() != Some(HEADER) {
+            return Err("Expected a benchmark report header.".to_string());
+        }
+        let mut results = Vec::new();
+        for (n, line) in lines.enumerate().filter(|&(_, l)| !l.is_empty()) {
+            let fields: Vec<&str> = line.split('\t').collect();
+            // Rates and times of zero or less, or not finite, would make
+            // every comparison against them meaningless.
+            let number = |s: &str| match s.parse::<f64>() {
+                Ok(x) if x.is_finite() && x > 0.0 => Ok(x),
+                Ok(_) => Err(format!("Expected a positive number on line {}.", n + 2)),
+                Err(_) => Err(format!("Malformed number on line {}.", n + 2)),
+            };
+            if fields.len() != 4 {
+                return Err(format!("Expected 4 fields on line {}.", n + 2));
+            }
+            results.push(BenchResult {
+                expr: fields[0].to_string(),
+                parse_ns: number(fields[1])?,
+                check_ns: number(fields[2])?,
+                samples_per_sec: number(fields[3])?,
+            });
+        }
+        Ok(Report(results))
+    }
+
+    // Lists each expression found in both reports with the relative change
+    // in its sampling rate, and whether that counts as a regression.
+    pub fn compare(&self, baseline: &Report) -> Vec<(String, f64, bool)> {
+        let mut changes = Vec::new();
+        for r in &self.0 {
+            if let Some(b) = baseline.0.iter().find(|b| b.expr == r.expr) {
+                let change = r.samples_per_sec / b.samples_per_sec - 1.0;
+                changes.push((r.expr.clone(), change, change < -TOLERANCE));
+            }
+        }
+        changes
+    }
+}
@@ -0,0 +1,139 @@
+use compare;
+use estimator::SudiceResults;
+
+use std::cmp::{self, Reverse};
+use std::fmt;
+use std::vec::Vec;
+
+// Each series is drawn with its own glyph, in the order given.
+pub const GLYPHS: &[char] = &['#', '*', 'o', '+', '@', '%', '=', 'x'];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Orientation {
+    // One row per outcome, with bars growing to the right.
+    Horizontal,
+    // One column per outcome, with bars growing upwards.
+    Vertical,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    // Every series shares one bar per outcome, shorter bars drawn over
+    // longer ones so that each stays visible.
+    Overlay,
+    // Every series gets a bar of its own per outcome, side by side.
+    Interleave,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Style {
+    // Length in characters of the bar for the likeliest outcome of any
+    // series, which is a width drawn horizontally or a height vertically.
+    pub width: usize,
+    pub orientation: Orientation,
+    pub layout: Layout,
+}
+
+impl Style {
+    pub fn new() -> Style {
+        Style { width: 40, orientation: Orientation::Horizontal, layout: Layout::Overlay }
+    }
+}
+
+impl fmt::Display for Style {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}, {:?}, width {}", self.orientation, self.layout, self.width)
+    }
+}
+
+// Several distributions drawn on a shared axis of outcomes.
+pub struct Chart<'a> {
+    pub series: &'a [(String, SudiceResults)],
+    pub style: Style,
+}
+
+fn glyph(s: usize) -> char {
+    GLYPHS[s % GLYPHS.len()]
+}
+
+impl<'a> Chart<'a> {
+    // Every outcome any series can produce, in increasing order, with the
+    // length of each series' bar for it.
+    fn bars(&self) -> (Vec<i64>, Vec<Vec<usize>>) {
+        let mut values: Vec<i64> = self.series.iter().flat_map(|(_, r)| r.values.iter().cloned()).collect();
+        values.sort_unstable();
+        values.dedup();
+        let mdist = self.series.iter().flat_map(|(_, r)| r.dist.iter().cloned()).fold(0.0, f64::max);
+        let bars = self.series.iter().map(|(_, r)| {
+            values.iter().map(|v| {
+                let p = r.values.binary_search(v).ok().map_or(0.0, |idx| r.dist[idx]);
+                (self.style.width as f64 * p / mdist).round() as usize
+            }).collect()
+        }).collect();
+        (values, bars)
+    }
+
+    // What to draw `at` characters along the bars for outcome `i`: the
+    // glyph of each series in turn when interleaved, or else just the glyph
+    // of the shortest bar reaching that far.
+    fn cells(&self, bars: &[Vec<usize>], i: usize, at: usize) -> Vec<char> {
+        let reaches = |s: &usize| bars[*s][i] > at;
+        match self.style.layout {
+            Layout::Interleave => (0..bars.len()).map(|s| if reaches(&s) { glyph(s) } else { ' ' }).collect(),
+            Layout::Overlay => {
+                let shortest = (0..bars.len()).filter(reaches).min_by_key(|&s| (bars[s][i], Reverse(s)));
+                vec![shortest.map_or(' ', glyph)]
+            },
+        }
+    }
+}
+
+impl<'a> fmt::Display for Chart<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (idx, (expr, _)) in self.series.iter().enumerate() {
+            writeln!(f, "{} {}: {}", glyph(idx), compare::name(idx), expr)?;
+        }
+        let (values, bars) = self.bars();
+        let labels: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        let lchars = labels.iter().map(|l| l.len()).max().unwrap_or(1);
+        let lanes = match self.style.layout {
+            Layout::Interleave => self.series.len(),
+            Layout::Overlay => 1,
+        };
+        match self.style.orientation {
+            Orientation::Horizontal => for (i, label) in labels.iter().enumerate() {
+                for lane in 0..lanes {
+                    let mut row = String::new();
+                    for at in 0..self.style.width {
+                        row.push(self.cells(&bars, i, at)[lane]);
+                    }
+                    let label = if lane == 0 { label.as_str() } else { "" };
+                    writeln!(f, "{:>width$} |{}", label, row.trim_end(), width = lchars)?;
+                }
+            },
+            Orientation::Vertical => {
+                // Each outcome gets a slot wide enough for its label or for
+                // one column per lane, whichever is wider.
+                let slot = cmp::max(lchars, lanes);
+                for at in (0..self.style.width).rev() {
+                    let mut row = String::new();
+                    for i in 0..values.len() {
+                        let cells = self.cells(&bars, i, at);
+                        for c in 0..slot {
+                            row.push(match self.style.layout {
+                                Layout::Overlay => cells[0],
+                                Layout::Interleave => *cells.get(c).unwrap_or(&' '),
+                            });
+                        }
+                        row.push(' ');
+                    }
+                    writeln!(f, "{}", row.trim_end())?;
+                }
+                writeln!(f, "{}", "-".repeat(values.len() * (slot + 1)))?;
+                let axis: Vec<String> = labels.iter().map(|l| format!("{:>width$}", l, width = slot)).collect();
+                writeln!(f, "{}", axis.join(" "))?;
+            },
+        }
+        Ok(())
+    }
+}
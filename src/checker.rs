@@ -1,263 +1,773 @@
-use descriptor::{SudiceCode, SudiceExpression};
+use descriptor::{self, from_bool, SudiceCode, SudiceError, SudiceExpression};
+use typeck;
 
 use std::cmp;
-use std::cmp::PartialOrd;
-use std::i64;
-use std::ops::{Add, Sub, Mul, Div};
+use std::mem;
+use std::slice;
 use std::vec::Vec;
 
-#[derive(Clone, Copy, Debug)]
+const OVERFLOW: &str = "Bounds of this expression overflow 64-bit integers.";
+
+// Support sets with more values than this are widened to their bounds, as
+// are operations which would have to combine more pairs of values.
+const SUPPORT_LIMIT: usize = 1 << 16;
+const PAIR_LIMIT: usize = 1 << 20;
+
+// Sums of dice are tracked with bitsets no wider than this, and only when
+// doing so takes at most WORK_LIMIT word operations.
+const WIDTH_LIMIT: u64 = 1 << 24;
+const WORK_LIMIT: u64 = 1 << 26;
+
+// An inclusive range of integers which a value is known to lie within.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Interval {
+    pub min: i64,
+    pub max: i64,
+}
+
+impl Interval {
+    fn new(min: i64, max: i64) -> Interval {
+        Interval { min, max }
+    }
+
+    fn point(x: i64) -> Interval {
+        Interval::new(x, x)
+    }
+
+    // The smallest interval containing every value in the list, or None if
+    // computing any of them overflowed.
+    fn hull(values: &[Option<i64>]) -> Option<Interval> {
+        let mut values = values.iter().cloned();
+        let first = values.next().unwrap()?;
+        values.try_fold(Interval::point(first), |r, x| x.map(|x| r.union(Interval::point(x))))
+    }
+
+    fn contains(&self, x: i64) -> bool {
+        self.min <= x && x <= self.max
+    }
+
+    fn union(&self, other: Interval) -> Interval {
+        Interval::new(cmp::min(self.min, other.min), cmp::max(self.max, other.max))
+    }
+}
+
+// Bounds a monotonic operation over two intervals, whose extremes must lie
+// at their corners.
+fn corners<F: Fn(i64, i64) -> Option<i64>>(x: Interval, y: Interval, f: F) -> Option<Interval> {
+    Interval::hull(&[f(x.min, y.min), f(x.min, y.max), f(x.max, y.min), f(x.max, y.max)])
+}
+
+// Bounds a division, which is only monotonic on either side of zero, so a
+// divisor straddling it is split into halves ending at -1 and 1.
+fn quotients<F: Fn(i64, i64) -> Option<i64>>(x: Interval, y: Interval, f: F) -> Option<Interval> {
+    if y.min < 0 && 0 < y.max {
+        let below = corners(x, Interval::new(y.min, -1), &f)?;
+        let above = corners(x, Interval::new(1, y.max), &f)?;
+        Some(below.union(above))
+    } else {
+        corners(x, y, f)
+    }
+}
+
+// The values an expression can take on. Small sets are tracked exactly so
+// that gaps, such as those in 10*1d2, are not mistaken for outcomes; large
+// ones are widened to the interval containing them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Support {
+    // Sorted and free of duplicates.
+    Set(Vec<i64>),
+    Range(Interval),
+}
+
+impl Support {
+    fn point(x: i64) -> Support {
+        Support::Set(vec![x])
+    }
+
+    fn range(min: i64, max: i64) -> Support {
+        Support::from_interval(Interval::new(min, max))
+    }
+
+    fn from_interval(i: Interval) -> Support {
+        if (i.max as i128) - (i.min as i128) < SUPPORT_LIMIT as i128 {
+            Support::Set((i.min..=i.max).collect())
+        } else {
+            Support::Range(i)
+        }
+    }
+
+    fn from_values(mut values: Vec<i64>) -> Support {
+        values.sort_unstable();
+        values.dedup();
+        if values.len() > SUPPORT_LIMIT {
+            Support::Range(Interval::new(values[0], values[values.len()-1]))
+        } else {
+            Support::Set(values)
+        }
+    }
+
+    pub fn bounds(&self) -> Interval {
+        match *self {
+            Support::Set(ref v) => Interval::new(v[0], v[v.len()-1]),
+            Support::Range(i) => i,
+        }
+    }
+
+    pub fn min(&self) -> i64 {
+        self.bounds().min
+    }
+
+    pub fn max(&self) -> i64 {
+        self.bounds().max
+    }
+
+    // The number of outcomes, saturating for ranges spanning most of i64.
+    pub fn len(&self) -> u64 {
+        match *self {
+            Support::Set(ref v) => v.len() as u64,
+            Support::Range(i) => cmp::min((i.max as i128) - (i.min as i128) + 1, u64::MAX as i128) as u64,
+        }
+    }
+
+    pub fn contains(&self, x: i64) -> bool {
+        self.index(x).is_some()
+    }
+
+    // The position of an outcome among all the outcomes, in increasing order.
+    pub fn index(&self, x: i64) -> Option<usize> {
+        match *self {
+            Support::Set(ref v) => v.binary_search(&x).ok(),
+            Support::Range(i) => if i.contains(x) { Some((x - i.min) as usize) } else { None },
+        }
+    }
+
+    pub fn values(&self) -> Vec<i64> {
+        match *self {
+            Support::Set(ref v) => v.clone(),
+            Support::Range(i) => (i.min..=i.max).collect(),
+        }
+    }
+
+    // The length of the longest run of consecutive values.
+    fn longest_run(&self) -> u64 {
+        match *self {
+            Support::Set(ref v) => {
+                let (mut longest, mut run) = (1, 1);
+                for w in v.windows(2) {
+                    run = if w[1] == w[0] + 1 { run + 1 } else { 1 };
+                    longest = cmp::max(longest, run);
+                }
+                longest
+            },
+            Support::Range(_) => self.len(),
+        }
+    }
+
+    fn union(&self, other: &Support) -> Support {
+        match (self, other) {
+            (Support::Set(a), Support::Set(b)) => {
+                let mut v = a.clone();
+                v.extend_from_slice(b);
+                Support::from_values(v)
+            },
+            _ => Support::Range(self.bounds().union(other.bounds())),
+        }
+    }
+
+    // Applies `f` to every value of a set; ranges are bounded by `widen`.
+    fn map<F, G>(&self, f: F, widen: G) -> Option<Support>
+        where F: Fn(i64) -> Option<i64>, G: Fn(Interval) -> Option<Interval> {
+        match *self {
+            Support::Set(ref v) => v.iter().map(|&x| f(x)).collect::<Option<Vec<i64>>>().map(Support::from_values),
+            Support::Range(i) => widen(i).map(Support::from_interval),
+        }
+    }
+
+    // Applies `f` to every pair of values when both operands are sets small
+    // enough, and otherwise bounds the result with `widen`.
+    fn combine<F, G>(&self, other: &Support, f: F, widen: G) -> Option<Support>
+        where F: Fn(i64, i64) -> Option<i64>, G: Fn(Interval, Interval) -> Option<Interval> {
+        if let (Support::Set(a), Support::Set(b)) = (self, other) {
+            if a.len() * b.len() <= PAIR_LIMIT {
+                let mut v = Vec::with_capacity(a.len() * b.len());
+                for &x in a {
+                    for &y in b {
+                        v.push(f(x, y)?);
+                    }
+                }
+                return Some(Support::from_values(v));
+            }
+        }
+        widen(self.bounds(), other.bounds()).map(Support::from_interval)
+    }
+
+    // The possible sums of a number of dice in `len` showing these faces.
+    fn sum(&self, len: Interval) -> Option<Support> {
+        // The sum is linear in the number of dice, so its extremes lie at
+        // the extremes of both the count and the faces.
+        let f = self.bounds();
+        let hull = corners(len, f, i64::checked_mul)?;
+        let faces = match *self {
+            Support::Set(ref faces) => faces,
+            Support::Range(_) => return Some(Support::Range(hull)),
+        };
+        let dice = (len.max - len.min) as u64 + 1;
+        if faces.len() as i128 == f.max as i128 - f.min as i128 + 1 && dice <= PAIR_LIMIT as u64 {
+            // n dice with consecutive faces can sum to anything between
+            // n times the lowest face and n times the highest.
+            let mut ranges: Vec<Interval> = (len.min..=len.max).map(|n| Interval::new(n * f.min, n * f.max)).collect();
+            ranges.sort_by_key(|r| r.min);
+            let mut merged: Vec<Interval> = Vec::with_capacity(ranges.len());
+            for r in ranges {
+                match merged.last_mut() {
+                    Some(m) if r.min <= m.max.saturating_add(1) => m.max = cmp::max(m.max, r.max),
+                    _ => merged.push(r),
+                }
+            }
+            let count = merged.iter().fold(0u64, |n, r| n.saturating_add((r.max as i128 - r.min as i128) as u64 + 1));
+            if merged.len() == 1 || count > SUPPORT_LIMIT as u64 {
+                return Some(Support::from_interval(hull));
+            }
+            return Some(Support::Set(merged.iter().flat_map(|r| r.min..=r.max).collect()));
+        }
+        let width = (hull.max as i128 - hull.min as i128 + 1) as u64;
+        let words = (width / 64 + 2) as usize;
+        if width > WIDTH_LIMIT || (len.max as u64) * (faces.len() as u64) * (words as u64) > WORK_LIMIT {
+            return Some(Support::from_interval(hull));
+        }
+        // Bit i of `sums` stands for n * f.min + i, and bit i of `result` for
+        // hull.min + i.
+        let mut sums = vec![0u64; words];
+        let mut result = vec![0u64; words];
+        sums[0] = 1;
+        for n in 0..=len.max {
+            if n >= len.min {
+                or_shifted(&mut result, &sums, (n * f.min - hull.min) as usize);
+            }
+            if n == len.max {
+                break;
+            }
+            let mut next = vec![0u64; words];
+            for &face in faces {
+                or_shifted(&mut next, &sums, (face - f.min) as usize);
+            }
+            sums = next;
+        }
+        let mut values = Vec::new();
+        for (i, &word) in result.iter().enumerate() {
+            for bit in 0..64 {
+                if word & (1 << bit) != 0 {
+                    values.push(hull.min + (i * 64 + bit) as i64);
+                }
+            }
+        }
+        Some(Support::from_values(values))
+    }
+}
+
+// Sets the bits of `dst` which are set in `src` after shifting it up by
+// `shift` bits.
+fn or_shifted(dst: &mut [u64], src: &[u64], shift: usize) {
+    let (words, rem) = (shift / 64, shift % 64);
+    for (i, &word) in src.iter().enumerate() {
+        if word == 0 || i + words >= dst.len() {
+            continue;
+        }
+        dst[i + words] |= word << rem;
+        if rem > 0 && i + words + 1 < dst.len() {
+            dst[i + words + 1] |= word >> (64 - rem);
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 enum CheckerValue {
-    Scalar(i64),
-    Vector(i64, i64),
+    Scalar(Support),
+    // A roll of some number of dice in `len`, each showing a face in `face`
+    // and having a number of sides in `size`, which rerolls draw from.
+    Vector { len: Interval, face: Support, size: Interval },
+    // Whichever of several values a select or a single repeat ran, passed
+    // on as it is, so that capping it caps the dice of a roll one by one.
+    Either(Vec<CheckerValue>),
 }
 
 impl CheckerValue {
-    fn collapse(&self) -> i64 {
+    fn collapse(&self) -> Option<Support> {
         match *self {
-            CheckerValue::Scalar(s) => s,
-            CheckerValue::Vector(l, s) => l * s,
+            CheckerValue::Scalar(ref s) => Some(s.clone()),
+            CheckerValue::Vector { len, ref face, .. } => face.sum(len),
+            CheckerValue::Either(ref values) => {
+                let mut supports = values.iter().map(CheckerValue::collapse);
+                let first = supports.next().unwrap()?;
+                supports.try_fold(first, |acc, s| Some(acc.union(&s?)))
+            },
         }
     }
 
-    fn true_value() -> CheckerValue {
-        CheckerValue::Scalar(1)
+    // Caps a scalar, or each die of a roll, with `cap`.
+    fn cap<F: Fn(&Support) -> Option<Support>>(&self, cap: &F) -> Option<CheckerValue> {
+        Some(match *self {
+            CheckerValue::Scalar(ref s) => CheckerValue::Scalar(cap(s)?),
+            CheckerValue::Vector { len, ref face, size } => CheckerValue::Vector { len, face: cap(face)?, size },
+            CheckerValue::Either(ref values) => CheckerValue::Either(values.iter().map(|v| v.cap(cap)).collect::<Option<_>>()?),
+        })
     }
+}
 
-    fn false_value() -> CheckerValue {
-        CheckerValue::Scalar(2)
+// Booleans are 1 when true and 2 when false.
+fn boolean(can_be_true: bool, can_be_false: bool) -> Option<Interval> {
+    match (can_be_true, can_be_false) {
+        (true, false) => Some(Interval::point(1)),
+        (false, true) => Some(Interval::point(2)),
+        _ => Some(Interval::new(1, 2)),
     }
 }
 
+// `vars` holds the values bound to each slot by the lets enclosing the
+// instruction being checked, and `tuple` the components of the tuple the
+// expression ended in, if it did.
 struct CheckerState {
-    pub min_s: Vec<CheckerValue>,
-    pub min_tos: CheckerValue,
-    pub max_s: Vec<CheckerValue>,
-    pub max_tos: CheckerValue,
+    pub s: Vec<CheckerValue>,
+    pub tos: CheckerValue,
+    pub vars: Vec<Support>,
+    pub tuple: Option<Vec<Support>>,
 }
 
 impl CheckerState {
-    fn new(capacity: usize) -> CheckerState {
+    fn new(d: &SudiceExpression) -> CheckerState {
         CheckerState {
-            min_s: Vec::with_capacity(capacity),
-            min_tos: CheckerValue::Scalar(0),
-            max_s: Vec::with_capacity(capacity),
-            max_tos: CheckerValue::Scalar(0)
+            s: Vec::with_capacity(d.code.len()),
+            tos: CheckerValue::Scalar(Support::point(0)),
+            vars: vec![Support::point(0); d.slots],
+            tuple: None,
         }
     }
 
-    fn push(&mut self, min: CheckerValue, max: CheckerValue) {
-        self.min_s.push(self.min_tos);
-        self.min_tos = min;
-        self.max_s.push(self.max_tos);
-        self.max_tos = max;
+    fn push(&mut self, value: CheckerValue) {
+        let tos = mem::replace(&mut self.tos, value);
+        self.s.push(tos);
     }
 
-    fn pop(&mut self) {
-        self.min_tos = self.min_s.pop().unwrap();
-        self.max_tos = self.max_s.pop().unwrap();
+    fn pop(&mut self) -> CheckerValue {
+        let x = self.s.pop().unwrap();
+        mem::replace(&mut self.tos, x)
     }
+}
 
-    fn nop(&mut self) {
-        let _ = self.min_s.pop().unwrap();
-        let _ = self.max_s.pop().unwrap();
+pub fn semantic_check(d: &SudiceExpression) -> Result<Support, SudiceError> {
+    let mut supports = joint_check(d)?;
+    if supports.len() > 1 {
+        return Err(SudiceError::new(d.spans[d.code.len()-1], "Expected a single value rather than a tuple."));
     }
+    Ok(supports.pop().unwrap())
 }
 
-pub fn semantic_check(d: &SudiceExpression) -> Result<(i64, i64), String> {
-    let mut state = CheckerState::new(d.code.len());
+// Checks an expression which may end in a tuple, giving the values each of
+// its components can take on, or just the one support of any other
+// expression. Components are bounded on their own, as if independent.
+pub fn joint_check(d: &SudiceExpression) -> Result<Vec<Support>, SudiceError> {
+    typeck::type_check(d)?;
+    let mut state = CheckerState::new(d);
     semantic_check_with(d, 0, false, &mut state)?;
-    Ok((state.min_tos.collapse(), state.max_tos.collapse()))
+    if let Some(supports) = state.tuple {
+        return Ok(supports);
+    }
+    match state.tos.collapse() {
+        Some(support) => Ok(vec![support]),
+        None => Err(SudiceError::new(d.spans[d.code.len()-1], OVERFLOW)),
+    }
 }
 
-fn semantic_check_with(d: &SudiceExpression, start: usize, until_jump: bool, state: &mut CheckerState) -> Result<(), String> {
-    let mut left_can_be_true = false;
-    let mut right_can_be_true = false;
-    macro_rules! arith_op {
-        ($func:path) => {{
-            let min_x = state.min_s.pop().unwrap().collapse();
-            let max_x = state.max_s.pop().unwrap().collapse();
-            state.min_tos = CheckerValue::Scalar($func(state.min_tos.collapse(), min_x));
-            state.max_tos = CheckerValue::Scalar($func(state.max_tos.collapse(), max_x));
+// Rejects results which could take on more than `cap` distinct values,
+// since sampling allocates a histogram bucket for every one of them.
+pub fn range_check(d: &SudiceExpression, support: &Support, cap: u64) -> Result<(), SudiceError> {
+    joint_range_check(d, slice::from_ref(support), cap)
+}
+
+// Tuples get a bucket for every combination of their components' values.
+pub fn joint_range_check(d: &SudiceExpression, supports: &[Support], cap: u64) -> Result<(), SudiceError> {
+    let len = supports.iter().fold(1u64, |n, s| n.saturating_mul(s.len()));
+    if len > cap {
+        let message = format!("Result could take on {} values, more than the cap of {}.", len, cap);
+        return Err(SudiceError::new(d.spans[d.code.len()-1], message));
+    }
+    Ok(())
+}
+
+fn semantic_check_with(d: &SudiceExpression, start: usize, until_jump: bool, state: &mut CheckerState) -> Result<(), SudiceError> {
+    let mut dcp = start;
+    macro_rules! error {
+        ($msg:expr) => {{
+            return Err(SudiceError::new(d.spans[dcp], $msg));
         }}
     }
-    macro_rules! arith_op_inv {
-        ($func:path) => {{
-            let min_x = state.min_s.pop().unwrap().collapse();
-            let max_x = state.max_s.pop().unwrap().collapse();
-            state.min_tos = CheckerValue::Scalar($func(state.min_tos.collapse(), max_x));
-            state.max_tos = CheckerValue::Scalar($func(state.max_tos.collapse(), min_x));
+    macro_rules! checked {
+        ($e:expr) => {
+            match $e {
+                Some(x) => x,
+                None => error!(OVERFLOW),
+            }
+        }
+    }
+    macro_rules! collapse {
+        ($v:expr) => { checked!($v.collapse()) }
+    }
+    // Binds the collapsed right operand, which sits below the left operand
+    // on the stack, and the collapsed left operand, in that order.
+    macro_rules! operands {
+        ($x:ident, $y:ident) => {
+            let $y = collapse!(state.s.pop().unwrap());
+            let $x = collapse!(state.tos);
+        }
+    }
+    macro_rules! corner_op {
+        ($func:expr) => {{
+            operands!(x, y);
+            state.tos = CheckerValue::Scalar(checked!(x.combine(&y, $func, |x, y| corners(x, y, $func))));
         }}
     }
     macro_rules! drop_op {
         () => {{
-            let min_x = state.min_s.pop().unwrap().collapse();
-            state.min_tos = match state.min_tos {
-                CheckerValue::Scalar(_) => return Err("Attempted to drop scalar.".to_string()),
-                CheckerValue::Vector(l, s) => {
-                    if min_x >= l {
-                        return Err("Attempted to drop too many values.".to_string());
+            let x = collapse!(state.s.pop().unwrap()).bounds();
+            state.tos = match state.tos {
+                CheckerValue::Scalar(_) | CheckerValue::Either(_) => error!("Attempted to drop scalar."),
+                CheckerValue::Vector { len, ref face, size } => {
+                    if x.min < 0 {
+                        error!("Attempted to drop negative amount of dice.");
                     }
-                    CheckerValue::Vector(l - min_x, s)
+                    if x.max >= len.min {
+                        error!("Attempted to drop too many values.");
+                    }
+                    CheckerValue::Vector { len: Interval::new(len.min - x.max, len.max - x.min), face: face.clone(), size }
                 },
             };
-            let max_x = state.max_s.pop().unwrap().collapse();
-            state.max_tos = match state.max_tos {
-                CheckerValue::Scalar(_) => panic!("Due to symmetry, this should not happen."),
-                CheckerValue::Vector(l, s) => {
-                    if max_x >= l {
-                        panic!("Due to symmetry, this should not happen.");
+        }}
+    }
+    // Rerolled dice may land on any face of the die, even one which was
+    // capped away beforehand. `$vet` is given the bounds of the operand, of
+    // the number of dice and of their size, and says what is wrong, if
+    // anything, with rerolling by that operand.
+    macro_rules! reroll_op {
+        ($vet:expr) => {{
+            let x = collapse!(state.s.pop().unwrap()).bounds();
+            state.tos = match state.tos {
+                CheckerValue::Scalar(_) | CheckerValue::Either(_) => error!("Attempted to reroll scalar."),
+                CheckerValue::Vector { len, ref face, size } => {
+                    let vet: fn(Interval, Interval, Interval) -> Option<&'static str> = $vet;
+                    if let Some(message) = vet(x, len, size) {
+                        error!(message);
                     }
-                    CheckerValue::Vector(l - max_x, s)
+                    CheckerValue::Vector { len, face: face.union(&Support::range(1, size.max)), size }
                 },
             };
         }}
     }
     macro_rules! cap_op {
         ($func:path) => {{
-            let min_x = state.min_s.pop().unwrap().collapse();
-            state.min_tos = match state.min_tos {
-                CheckerValue::Scalar(s) => if $func(&s, &min_x) { CheckerValue::Scalar(min_x) } else { state.min_tos },
-                CheckerValue::Vector(l, s) => if $func(&s, &min_x) { CheckerValue::Vector(l, min_x) } else { state.min_tos },
-            };
-            let max_x = state.max_s.pop().unwrap().collapse();
-            state.max_tos = match state.max_tos {
-                CheckerValue::Scalar(s) => if $func(&s, &max_x) { CheckerValue::Scalar(max_x) } else { state.max_tos },
-                CheckerValue::Vector(l, s) => if $func(&s, &max_x) { CheckerValue::Vector(l, max_x) } else { state.max_tos },
-            };
+            let x = collapse!(state.s.pop().unwrap());
+            let f = |a, b| Some($func(a, b));
+            let cap = |v: &Support| v.combine(&x, f, |a, b| corners(a, b, f));
+            state.tos = checked!(state.tos.cap(&cap));
         }}
     }
+    // `$widen` says whether the comparison can hold, and whether it can
+    // fail, for some pair of values drawn from two intervals.
     macro_rules! cmp_op {
-        ($e1:expr, $e2:expr) => {{
-            let left_min = state.min_s.pop().unwrap().collapse();
-            let left_max = state.max_s.pop().unwrap().collapse();
-            let right_min = state.min_tos.collapse();
-            let right_max = state.max_tos.collapse();
-            if left_max < right_min {
-                state.min_tos = $e1;
-                state.max_tos = $e1;
-            } else if right_max < left_min {
-                state.min_tos = $e2;
-                state.max_tos = $e2;
-            } else {
-                state.min_tos = CheckerValue::true_value();
-                state.max_tos = CheckerValue::false_value();
-            }
+        ($op:tt, $widen:expr) => {{
+            operands!(left, right);
+            let widen: fn(Interval, Interval) -> (bool, bool) = $widen;
+            let result = left.combine(&right, |l, r| Some(from_bool(l $op r)), |l, r| {
+                let (can_be_true, can_be_false) = widen(l, r);
+                boolean(can_be_true, can_be_false)
+            });
+            state.tos = CheckerValue::Scalar(checked!(result));
         }}
     }
+    // An operand can be true if it may be 1, and false if it may be
+    // anything else; `$dual` combines the latter as `$op` does the former.
     macro_rules! logic_op {
-        ($e:expr) => {{
-            let left_min = state.min_s.pop().unwrap().collapse();
-            let left_max = state.max_s.pop().unwrap().collapse();
-            let right_min = state.min_tos.collapse();
-            let right_max = state.max_tos.collapse();   
-            left_can_be_true = left_min <= 1 && 1 <= left_max;
-            right_can_be_true = right_min <= 1 && 1 <= right_max;
-            if $e {
-                state.min_tos = CheckerValue::true_value();
-                state.max_tos = CheckerValue::false_value();
-            } else {
-                state.min_tos = CheckerValue::false_value();
-                state.max_tos = CheckerValue::false_value();
-            }
+        ($op:tt, $dual:tt) => {{
+            operands!(left, right);
+            let result = left.combine(&right, |l, r| Some(from_bool((l == 1) $op (r == 1))), |l, r| {
+                let (lt, lf) = (l.contains(1), l != Interval::point(1));
+                let (rt, rf) = (r.contains(1), r != Interval::point(1));
+                boolean(lt $op rt, lf $dual rf)
+            });
+            state.tos = CheckerValue::Scalar(checked!(result));
         }}
     }
 
-    let mut dcp = start;
     while dcp < d.code.len() {
         match d.code[dcp] {
-            SudiceCode::Num(i) => state.push(CheckerValue::Scalar(i), CheckerValue::Scalar(i)),
-            SudiceCode::Add => arith_op!(i64::add),
-            SudiceCode::Sub => arith_op_inv!(i64::sub),
-            SudiceCode::Mul => arith_op!(i64::mul),
-            SudiceCode::Div => arith_op_inv!(i64::div),
+            SudiceCode::Num(i) => state.push(CheckerValue::Scalar(Support::point(i))),
+            SudiceCode::Bool(b) => state.push(CheckerValue::Scalar(Support::point(from_bool(b)))),
+            SudiceCode::RollConst(rolls, size) => {
+                if rolls < 0 {
+                    error!("Attempted to roll negative amount of dice.");
+                }
+                if size <= 0 {
+                    error!("Attempted to roll dice size <= 0.");
+                }
+                let (len, size) = (Interval::point(rolls), Interval::point(size));
+                state.push(CheckerValue::Vector { len, face: Support::range(1, size.max), size });
+            },
+            SudiceCode::Add => corner_op!(i64::checked_add),
+            SudiceCode::Sub => corner_op!(i64::checked_sub),
+            SudiceCode::Mul => corner_op!(i64::checked_mul),
+            SudiceCode::Div | SudiceCode::DivCeil | SudiceCode::DivRound => {
+                if collapse!(state.s[state.s.len()-1]).contains(0) {
+                    error!("Attempted to divide by a range containing zero.");
+                }
+                let div: fn(i64, i64) -> Option<i64> = match d.code[dcp] {
+                    SudiceCode::Div => descriptor::checked_div_floor,
+                    SudiceCode::DivCeil => descriptor::checked_div_ceil,
+                    _ => descriptor::checked_div_round,
+                };
+                // Away from zero, division is monotonic in both arguments,
+                // and rounding it either way keeps it so, so the extremes are
+                // found at the corners of each side of the divisor.
+                operands!(x, y);
+                state.tos = CheckerValue::Scalar(checked!(x.combine(&y, div, |x, y| quotients(x, y, div))));
+            },
+            SudiceCode::Mod => {
+                if collapse!(state.s[state.s.len()-1]).contains(0) {
+                    error!("Attempted to divide by a range containing zero.");
+                }
+                operands!(x, y);
+                // The remainder takes the sign of the divisor, which lies wholly
+                // to one side of zero, and is smaller than it in magnitude. A
+                // dividend of that same sign is no smaller than it either.
+                let result = x.combine(&y, descriptor::checked_rem_floor, |x, y| {
+                    Some(if y.min > 0 {
+                        Interval::new(0, if x.min >= 0 { cmp::min(x.max, y.max - 1) } else { y.max - 1 })
+                    } else {
+                        Interval::new(if x.max <= 0 { cmp::max(x.min, y.min + 1) } else { y.min + 1 }, 0)
+                    })
+                });
+                state.tos = CheckerValue::Scalar(checked!(result));
+            },
+            SudiceCode::Pow => {
+                if collapse!(state.s[state.s.len()-1]).min() < 0 {
+                    error!("Attempted to raise to a negative power.");
+                }
+                operands!(x, y);
+                // For a given exponent the extremes lie at either end of the
+                // base or at zero, and the largest magnitudes of either sign
+                // at the two largest exponents, the smallest at the two least.
+                let result = x.combine(&y, descriptor::checked_pow, |x, y| {
+                    let mut bases = vec![x.min, x.max];
+                    if x.contains(0) {
+                        bases.push(0);
+                    }
+                    let exponents = [y.min, cmp::min(y.min.saturating_add(1), y.max), cmp::max(y.max - 1, y.min), y.max];
+                    let values: Vec<Option<i64>> = bases.iter()
+                        .flat_map(|&b| exponents.iter().map(move |&e| descriptor::checked_pow(b, e)))
+                        .collect();
+                    Interval::hull(&values)
+                });
+                state.tos = CheckerValue::Scalar(checked!(result));
+            },
+            SudiceCode::Min => corner_op!(|x, y| Some(cmp::min(x, y))),
+            SudiceCode::Max => corner_op!(|x, y| Some(cmp::max(x, y))),
             SudiceCode::Roll => {
-                let min_x = state.min_s.pop().unwrap().collapse();
-                let max_x = state.max_s.pop().unwrap().collapse();
-                let min_rolls = state.min_tos.collapse();
-                let max_rolls = state.max_tos.collapse();
-                if min_rolls < 0 || max_rolls < 0 {
-                    return Err("Attempted to roll negative amount of dice.".to_string());
+                operands!(rolls, size);
+                let (rolls, size) = (rolls.bounds(), size.bounds());
+                if rolls.min < 0 {
+                    error!("Attempted to roll negative amount of dice.");
                 }
-                if min_x <= 0 || max_x <= 0 {
-                    return Err("Attempted to roll dice size <= 0.".to_string());
+                if size.min <= 0 {
+                    error!("Attempted to roll dice size <= 0.");
                 }
-                state.min_tos = CheckerValue::Vector(min_rolls, 1);
-                state.max_tos = CheckerValue::Vector(max_rolls, max_x);
+                state.tos = CheckerValue::Vector { len: rolls, face: Support::range(1, size.max), size };
             },
-            SudiceCode::Reroll => state.nop(),
-            SudiceCode::RerollLowest => state.nop(),
-            SudiceCode::RerollHighest => state.nop(),
+            SudiceCode::Reroll => reroll_op!(|_, _, _| None),
+            SudiceCode::RerollLowest | SudiceCode::RerollHighest => reroll_op!(|x, len, _| {
+                if x.min < 0 {
+                    Some("Attempted to reroll negative amount of dice.")
+                } else if x.max > len.min {
+                    Some("Attempted to reroll too many dice.")
+                } else {
+                    None
+                }
+            }),
+            // A threshold past which every face of the die might lie is
+            // surely a mistake, as is rerolling a one-sided die until it
+            // stops showing 1.
+            SudiceCode::RerollUntil => reroll_op!(|x, _, size| {
+                if size.min == 1 && x.contains(1) { Some("Attempted to reroll every face of the die.") } else { None }
+            }),
+            SudiceCode::RerollBelow | SudiceCode::RerollBelowUntil => reroll_op!(|x, _, size| {
+                if x.max > size.min { Some("Attempted to reroll every face of the die.") } else { None }
+            }),
+            SudiceCode::RerollAbove | SudiceCode::RerollAboveUntil => reroll_op!(|x, _, _| {
+                if x.min < 1 { Some("Attempted to reroll every face of the die.") } else { None }
+            }),
             SudiceCode::DropLowest => drop_op!(),
             SudiceCode::DropHighest => drop_op!(),
-            SudiceCode::Ceil => cap_op!(i64::gt),
-            SudiceCode::Floor => cap_op!(i64::lt),
-            SudiceCode::BestOf(_) => state.nop(),
-            SudiceCode::WorstOf(_) => state.nop(),
-            SudiceCode::Select(ref offsets) => {
-                let len = offsets.len();
-                let first_min = state.min_tos.collapse();
-                let first_max = state.max_tos.collapse();
-                let bound_min = if first_min < 1 { 0 } else { first_min-1 };
-                let bound_max = if first_max >= (len-2) as i64 { (len-2) as i64 } else { first_max-1 };
-                let (mut min, mut max) = (i64::MAX, i64::MIN);
-                macro_rules! recursive_check {
-                    ($e:expr) => {{
-                        semantic_check_with(d, dcp + $e + 1, true, state)?;
-                        let new_min = state.min_tos.collapse();
-                        let new_max = state.max_tos.collapse();
-                        if new_min < min { min = new_min; }
-                        if new_max > max { max = new_max; }
-                        state.pop();
-                    }}
+            SudiceCode::Ceil => cap_op!(cmp::min),
+            SudiceCode::Floor => cap_op!(cmp::max),
+            // Picking one of several runs of an expression leaves its bounds
+            // alone, and the result is summed unless there is just the one.
+            SudiceCode::BestOf(_) | SudiceCode::WorstOf(_) => {
+                let x = collapse!(state.s.pop().unwrap());
+                if x.min() < 1 {
+                    error!("Attempted to repeat an expression less than once.");
                 }
-                if bound_min == 0 {
-                    recursive_check!(0);
+                if x.max() > 1 {
+                    let sum = CheckerValue::Scalar(collapse!(state.tos));
+                    state.tos = if x.min() == 1 {
+                        CheckerValue::Either(vec![mem::replace(&mut state.tos, sum.clone()), sum])
+                    } else {
+                        sum
+                    };
                 }
-                if bound_min < (len-1) as i64 && bound_max >= 0 { 
-                    for i in bound_min..bound_max {
-                        recursive_check!(offsets[i as usize]);
-                    }
+            },
+            // Independent runs of an expression add up as dice do, each run
+            // standing in for a die whose faces are the expression's values.
+            // Collected runs have no die to reroll them with, which the type
+            // check makes sure nothing tries.
+            SudiceCode::SumOf(_) | SudiceCode::CollectOf(_) => {
+                let x = collapse!(state.s.pop().unwrap()).bounds();
+                if x.min < 1 {
+                    error!("Attempted to repeat an expression less than once.");
                 }
-                if first_min < 1 || first_max >= (len-2) as i64 {
-                    recursive_check!(offsets[len-2]);
+                let face = collapse!(state.tos);
+                let runs = CheckerValue::Vector { len: x, face, size: Interval::point(0) };
+                state.tos = match d.code[dcp] {
+                    SudiceCode::SumOf(_) => CheckerValue::Scalar(collapse!(runs)),
+                    _ => runs,
+                };
+            },
+            // However the dice land, n of them showing m faces between them
+            // make a set of at least n/m, rounded up, and leave at most m
+            // of them unpaired.
+            SudiceCode::Largest | SudiceCode::Pairs | SudiceCode::Longest | SudiceCode::Unique | SudiceCode::Highest => {
+                let k = match d.code[dcp] {
+                    SudiceCode::Highest => Some(collapse!(state.s.pop().unwrap()).bounds()),
+                    _ => None,
+                };
+                let (len, face) = match state.tos {
+                    CheckerValue::Vector { len, ref face, .. } => (len, face.clone()),
+                    CheckerValue::Scalar(_) | CheckerValue::Either(_) => error!("Attempted to match faces of scalar."),
+                };
+                let m = face.len();
+                let least_set = |n: i64| if n == 0 { 0 } else { ((n as u64 - 1) / m + 1) as i64 };
+                let some = (len.min > 0) as i64;
+                let most = |x: u64| cmp::min(len.max as u64, x) as i64;
+                state.tos = CheckerValue::Scalar(match d.code[dcp] {
+                    SudiceCode::Largest => Support::range(least_set(len.min), len.max),
+                    SudiceCode::Pairs => Support::range((cmp::max(len.min as u64, m) - m).div_ceil(2) as i64, len.max / 2),
+                    SudiceCode::Longest => Support::range(some, most(face.longest_run())),
+                    SudiceCode::Unique => Support::range(some, most(m)),
+                    _ => {
+                        let k = k.unwrap();
+                        if k.min < 1 {
+                            error!("Attempted to match sets of fewer than one die.");
+                        }
+                        // There may be no set of k dice, which gives 0.
+                        let faces = k.min <= len.max;
+                        let none = len.min == 0 || k.max > least_set(len.min);
+                        match (faces, none) {
+                            (true, true) => face.union(&Support::point(0)),
+                            (true, false) => face,
+                            _ => Support::point(0),
+                        }
+                    },
+                });
+            },
+            SudiceCode::Select(ref offsets) => {
+                let len = offsets.len();
+                let pred = collapse!(state.pop());
+                let mut result = Vec::new();
+                // A predicate of 1 selects the first branch, 2 through len-1
+                // select the rest in order, and anything else the default.
+                let mut branch = dcp + 1;
+                for (i, offset) in offsets.iter().enumerate() {
+                    let reachable = if i < len - 1 {
+                        pred.contains(i as i64 + 1)
+                    } else {
+                        pred.min() < 1 || pred.max() > (len - 1) as i64
+                    };
+                    if reachable {
+                        semantic_check_with(d, branch, true, state)?;
+                        result.push(state.pop());
+                    }
+                    branch = dcp + offset + 1;
                 }
-                state.min_tos = CheckerValue::Scalar(min);
-                state.max_tos = CheckerValue::Scalar(max);
+                state.push(if result.len() == 1 { result.pop().unwrap() } else { CheckerValue::Either(result) });
                 dcp += offsets[len-1];
             },
+            SudiceCode::Label(_) => {},
+            SudiceCode::Bind(slot, _) => {
+                state.vars[slot] = collapse!(state.tos);
+                let _ = state.pop();
+            },
+            SudiceCode::Load(slot) => {
+                let value = CheckerValue::Scalar(state.vars[slot].clone());
+                state.push(value);
+            },
+            SudiceCode::Tuple(n) => {
+                let mut supports = vec![collapse!(state.tos)];
+                for _ in 1..n {
+                    supports.push(collapse!(state.s.pop().unwrap()));
+                }
+                state.tuple = Some(supports);
+            },
+            // Whatever the condition rules out of the result is still
+            // counted as possible.
+            SudiceCode::Given => {
+                let cond = collapse!(state.s.pop().unwrap());
+                if !cond.contains(1) {
+                    error!("Condition can never hold.");
+                }
+            },
             SudiceCode::Jump(_) => if until_jump {
                 return Ok(());
             } else {
                 panic!("Error: Illegal bytecode sequence: Should not reach jump!");
             },
-            SudiceCode::Lt => cmp_op!(CheckerValue::true_value(), CheckerValue::false_value()),
-            SudiceCode::Gt => cmp_op!(CheckerValue::false_value(), CheckerValue::true_value()),
-            SudiceCode::Eq => cmp_op!(CheckerValue::false_value(), CheckerValue::false_value()),
-            SudiceCode::Ne => cmp_op!(CheckerValue::true_value(), CheckerValue::true_value()),
-            SudiceCode::And => logic_op!(left_can_be_true && right_can_be_true),
-            SudiceCode::Or => logic_op!(left_can_be_true || right_can_be_true),
+            SudiceCode::Lt => cmp_op!(<, |l, r| (l.min < r.max, l.max >= r.min)),
+            SudiceCode::Gt => cmp_op!(>, |l, r| (l.max > r.min, l.min <= r.max)),
+            SudiceCode::Eq => cmp_op!(==, |l, r| (l.max >= r.min && r.max >= l.min, l.min != l.max || l != r)),
+            SudiceCode::Ne => cmp_op!(!=, |l, r| (l.min != l.max || l != r, l.max >= r.min && r.max >= l.min)),
+            SudiceCode::And => logic_op!(&&, ||),
+            SudiceCode::Or => logic_op!(||, &&),
+            SudiceCode::Xor => {
+                operands!(left, right);
+                let result = left.combine(&right, |l, r| Some(from_bool((l == 1) != (r == 1))), |l, r| {
+                    let (lt, lf) = (l.contains(1), l != Interval::point(1));
+                    let (rt, rf) = (r.contains(1), r != Interval::point(1));
+                    boolean((lt && rf) || (lf && rt), (lt && rt) || (lf && rf))
+                });
+                state.tos = CheckerValue::Scalar(checked!(result));
+            },
+            SudiceCode::Not => {
+                let x = collapse!(state.tos);
+                let result = x.map(|x| Some(from_bool(x != 1)), |x| boolean(x != Interval::point(1), x.contains(1)));
+                state.tos = CheckerValue::Scalar(checked!(result));
+            },
+            SudiceCode::Count => {
+                let x = collapse!(state.tos);
+                let result = x.map(|x| Some((x == 1) as i64), |x| {
+                    Some(Interval::new((x == Interval::point(1)) as i64, x.contains(1) as i64))
+                });
+                state.tos = CheckerValue::Scalar(checked!(result));
+            },
             SudiceCode::Abs => {
-                let min_top = state.min_tos.collapse();
-                let max_top = state.max_tos.collapse();
-                if min_top >= 0 && max_top >= 0 {
-                    state.min_tos = CheckerValue::Scalar(min_top);
-                    state.max_tos = CheckerValue::Scalar(max_top);
-                } else if min_top < 0 && max_top < 0 {
-                    state.min_tos = CheckerValue::Scalar(max_top.abs());
-                    state.max_tos = CheckerValue::Scalar(min_top.abs());
-                } else if min_top < 0 && max_top >= 0 {
-                    state.min_tos = CheckerValue::Scalar(0);
-                    state.max_tos = CheckerValue::Scalar(cmp::max(max_top, min_top.abs()));
+                let x = collapse!(state.tos);
+                let result = x.map(i64::checked_abs, |x| if x.min >= 0 {
+                    Some(x)
+                } else if x.max < 0 {
+                    Some(Interval::new(-x.max, x.min.checked_neg()?))
                 } else {
-                    return Err("Internal: Inconsistent state found (min >= 0, max < 0) during check.".to_string());
-                }
+                    Some(Interval::new(0, cmp::max(x.max, x.min.checked_neg()?)))
+                });
+                state.tos = CheckerValue::Scalar(checked!(result));
             },
             SudiceCode::Neg => {
-                let min_top = state.min_tos.collapse();
-                let max_top = state.max_tos.collapse();
-                state.min_tos = CheckerValue::Scalar(-max_top);
-                state.max_tos = CheckerValue::Scalar(-min_top);
+                let x = collapse!(state.tos);
+                let result = x.map(i64::checked_neg, |x| Some(Interval::new(x.max.checked_neg()?, x.min.checked_neg()?)));
+                state.tos = CheckerValue::Scalar(checked!(result));
             },
         }
         dcp += 1;
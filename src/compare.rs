@@ -0,0 +1,97 @@
+use estimator::SudiceResults;
+
+use std::fmt;
+use std::vec::Vec;
+
+// Standard normal quantile for a two-sided 95% confidence interval.
+const Z_95: f64 = 1.96;
+
+const BAR_WIDTH: f64 = 20.0;
+
+// The chances that a roll of one expression comes out above, level with or
+// below an independent roll of another.
+#[derive(Debug)]
+pub struct Odds {
+    pub gt: f64,
+    pub eq: f64,
+    pub lt: f64,
+}
+
+pub fn odds(a: &SudiceResults, b: &SudiceResults) -> Odds {
+    let (mut gt, mut eq) = (0.0f64, 0.0f64);
+    let mut below = 0.0f64;
+    let mut j = 0;
+    for (idx, &x) in a.values.iter().enumerate() {
+        while j < b.values.len() && b.values[j] < x {
+            below += b.dist[j];
+            j += 1;
+        }
+        if j < b.values.len() && b.values[j] == x {
+            eq += a.dist[idx] * b.dist[j];
+        }
+        gt += a.dist[idx] * below;
+    }
+    Odds { gt, eq, lt: (1.0 - gt - eq).max(0.0) }
+}
+
+// The estimated difference between the expected values of `a` and `b`,
+// with the half-width of its 95% confidence interval. The two estimates are
+// drawn independently, so their standard errors add in quadrature.
+pub fn mean_difference(a: &SudiceResults, b: &SudiceResults) -> (f64, f64) {
+    let se = (a.sd * a.sd / a.total as f64 + b.sd * b.sd / b.total as f64).sqrt();
+    (a.ev - b.ev, Z_95 * se)
+}
+
+// Two or more expressions, each with its estimate, named A, B, C and so on
+// in the order given.
+pub struct Comparison(pub Vec<(String, SudiceResults)>);
+
+pub fn name(idx: usize) -> char {
+    (b'A' + idx as u8) as char
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "##### [ COMPARISON ] #####")?;
+        for (idx, (expr, r)) in self.0.iter().enumerate() {
+            writeln!(f, "{}: {}\tEV {:.4}\tSD {:.4}", name(idx), expr, r.ev, r.sd)?;
+        }
+        for i in 0..self.0.len() {
+            for j in i + 1..self.0.len() {
+                let (a, b) = (&self.0[i].1, &self.0[j].1);
+                let (x, y) = (name(i), name(j));
+                let o = odds(a, b);
+                let (diff, ci) = mean_difference(a, b);
+                writeln!(f, "{} vs {}:", x, y)?;
+                writeln!(f, "  P({} > {}):\t{:.2}%", x, y, o.gt * 100.0)?;
+                writeln!(f, "  P({} = {}):\t{:.2}%", x, y, o.eq * 100.0)?;
+                writeln!(f, "  P({} < {}):\t{:.2}%", x, y, o.lt * 100.0)?;
+                writeln!(f, "  E[{}] - E[{}]:\t{:.4} ± {:.4}", x, y, diff, ci)?;
+            }
+        }
+
+        // One row for every outcome any of the expressions can produce, with
+        // a column for each, all scaled against the likeliest outcome overall.
+        writeln!(f, "##### [ DISTRIBUTIONS ] #####")?;
+        let mut values: Vec<i64> = self.0.iter().flat_map(|(_, r)| r.values.iter().cloned()).collect();
+        values.sort_unstable();
+        values.dedup();
+        let ichars = values.iter().map(|v| v.to_string().len()).max().unwrap_or(1);
+        let mdist = self.0.iter().flat_map(|(_, r)| r.dist.iter().cloned()).fold(0.0, f64::max);
+        write!(f, "{:>width$} ", "", width = ichars)?;
+        for idx in 0..self.0.len() {
+            write!(f, "| {:<width$} ", name(idx), width = 7 + BAR_WIDTH as usize)?;
+        }
+        writeln!(f)?;
+        for &v in &values {
+            write!(f, "{:>width$} ", v, width = ichars)?;
+            for (_, r) in &self.0 {
+                let p = r.values.binary_search(&v).ok().map_or(0.0, |idx| r.dist[idx]);
+                let bar = "#".repeat((BAR_WIDTH * p / mdist).round() as usize);
+                write!(f, "| {:>5.1}% {:<width$} ", p * 100.0, bar, width = BAR_WIDTH as usize)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
@@ -1,35 +1,248 @@
+use std::cmp;
+use std::convert::TryFrom;
+use std::fmt;
 use std::vec::Vec;
 
-#[derive(Debug)]
+// The most times a single die is rerolled by a reroll-until, which keeps
+// whatever the die shows after that. Capping it keeps every expression
+// finite, so that its distribution can still be worked out exactly.
+pub const REROLL_DEPTH: usize = 100;
+
+// Raises `x` to the power `y`, or None if `y` is negative or the result
+// overflows. Exponents too large for pow are still fine for 0, 1 and -1.
+pub fn checked_pow(x: i64, y: i64) -> Option<i64> {
+    match x {
+        _ if y < 0 => None,
+        0 => Some(if y == 0 { 1 } else { 0 }),
+        1 => Some(1),
+        -1 => Some(if y % 2 == 0 { 1 } else { -1 }),
+        _ => u32::try_from(y).ok().and_then(|y| x.checked_pow(y)),
+    }
+}
+
+pub fn saturating_pow(x: i64, y: i64) -> i64 {
+    checked_pow(x, y).unwrap_or(if x < 0 && y % 2 != 0 { i64::MIN } else { i64::MAX })
+}
+
+// Divides `x` by `y`, rounding down, or None if `y` is zero or the result
+// overflows, which only dividing i64::MIN by -1 does.
+pub fn checked_div_floor(x: i64, y: i64) -> Option<i64> {
+    let q = x.checked_div(y)?;
+    Some(if x % y != 0 && (x < 0) != (y < 0) { q - 1 } else { q })
+}
+
+pub fn checked_div_ceil(x: i64, y: i64) -> Option<i64> {
+    let q = x.checked_div(y)?;
+    Some(if x % y != 0 && (x < 0) == (y < 0) { q + 1 } else { q })
+}
+
+// What is left over from dividing `x` by `y` rounding down, which takes the
+// sign of the divisor, so that `x == (x / y) * y + x % y`. None if `y` is
+// zero; unlike the quotient, it never overflows.
+pub fn checked_rem_floor(x: i64, y: i64) -> Option<i64> {
+    if y == 0 {
+        return None;
+    }
+    let r = x.wrapping_rem(y);
+    Some(if r != 0 && (r < 0) != (y < 0) { r + y } else { r })
+}
+
+// Rounds to the nearest integer, halves rounding up.
+pub fn checked_div_round(x: i64, y: i64) -> Option<i64> {
+    let q = checked_div_floor(x, y)?;
+    // The remainder is the fraction of the divisor by which the quotient
+    // was rounded down.
+    let r = checked_rem_floor(x, y)?;
+    Some(if 2 * r.unsigned_abs() as u128 >= y.unsigned_abs() as u128 { q + 1 } else { q })
+}
+
+// The sets of dice showing the same face in a pool sorted in increasing
+// order, each as its face and the number of dice in it.
+fn sets(faces: &[i64]) -> Vec<(i64, usize)> {
+    let mut sets: Vec<(i64, usize)> = Vec::new();
+    for &face in faces {
+        match sets.last_mut() {
+            Some(&mut (last, ref mut n)) if last == face => *n += 1,
+            _ => sets.push((face, 1)),
+        }
+    }
+    sets
+}
+
+// The number of dice in the largest set.
+pub fn largest(faces: &[i64]) -> i64 {
+    sets(faces).into_iter().map(|(_, n)| n).max().unwrap_or(0) as i64
+}
+
+// The number of pairs the dice can be matched into, each die in at most one.
+pub fn pairs(faces: &[i64]) -> i64 {
+    sets(faces).into_iter().map(|(_, n)| n / 2).sum::<usize>() as i64
+}
+
+// The length of the longest run of consecutive faces each shown by a die.
+pub fn longest(faces: &[i64]) -> i64 {
+    let (mut longest, mut run, mut last) = (0, 0, None);
+    for (face, _) in sets(faces) {
+        run = if last.and_then(|l: i64| l.checked_add(1)) == Some(face) { run + 1 } else { 1 };
+        longest = cmp::max(longest, run);
+        last = Some(face);
+    }
+    longest
+}
+
+pub fn unique(faces: &[i64]) -> i64 {
+    sets(faces).len() as i64
+}
+
+// The highest face shown by at least `k` dice, or 0 if there is none.
+pub fn highest(faces: &[i64], k: i64) -> i64 {
+    sets(faces).into_iter().rev().find(|&(_, n)| n as i64 >= k).map_or(0, |(face, _)| face)
+}
+
+// Conditions come out as 1 for true or 2 for false, as `Bool` pushes them.
+pub fn from_bool(x: bool) -> i64 {
+    if x { 1 } else { 2 }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum SudiceCode {
     Num(i64),
+    // Pushes 1 for true or 2 for false, like a comparison would, but is
+    // kept apart from Num so that the type checker knows it is a boolean.
+    Bool(bool),
+    // Rolls a fixed number of dice of a fixed size, as the optimizer emits
+    // in place of `Num Num Roll`.
+    RollConst(i64, i64),
     Add,
     Sub,
     Mul,
+    // Division rounding down, towards negative infinity.
     Div,
+    DivCeil,
+    // Division rounding to the nearest integer, halves rounding up.
+    DivRound,
+    // The remainder of division rounding down, taking the sign of the divisor.
+    Mod,
+    Pow,
+    Min,
+    Max,
     Roll,
     Reroll,
     RerollLowest,
     RerollHighest,
+    RerollBelow,
+    RerollAbove,
+    // Reroll dice again and again while they still match, up to
+    // REROLL_DEPTH times each, rather than just once.
+    RerollUntil,
+    RerollBelowUntil,
+    RerollAboveUntil,
     DropLowest,
     DropHighest,
     Ceil,
     Floor,
     BestOf(usize),
     WorstOf(usize),
+    // Sums the given number of independent runs of its operand.
+    SumOf(usize),
+    // Keeps each of the given number of independent runs of its operand,
+    // as if they were the faces of a roll, so that they can be dropped or
+    // capped just as dice can.
+    CollectOf(usize),
+    // Matches up the faces of a pool of dice or of collected results.
+    Largest,
+    Pairs,
+    Longest,
+    Unique,
+    Highest,
     Select(Vec<usize>),
     Jump(usize),
+    // Ends a select branch which was given a label, passing its result
+    // through but marking the sample as having taken that branch.
+    Label(String),
+    // Pops the value of a let into the given slot, where it stays for the
+    // given number of instructions which follow, the body of the let.
+    Bind(usize, usize),
+    // Pushes the value bound to the given slot.
+    Load(usize),
+    // Pops the given number of values, the first of them on top, into the
+    // components of a tuple, which only ever ends an expression.
+    Tuple(usize),
+    // Pops a condition from beneath the result of the whole expression,
+    // discarding the sample unless it holds.
+    Given,
     Lt,
     Gt,
     Eq,
     Ne,
     And,
     Or,
+    Xor,
+    Not,
+    // Turns a boolean into 1 when true and 0 when false, so that successes
+    // can be counted by summing.
+    Count,
     Abs,
     Neg
 }
 
+impl SudiceCode {
+    // Net change in stack depth from executing this instruction. A select
+    // pops its predicate; the branch it jumps to pushes the result.
+    pub fn stack_effect(&self) -> isize {
+        match *self {
+            SudiceCode::Num(_) | SudiceCode::Bool(_) | SudiceCode::RollConst(..) | SudiceCode::Load(_) => 1,
+            SudiceCode::Not | SudiceCode::Count | SudiceCode::Abs | SudiceCode::Neg |
+            SudiceCode::Largest | SudiceCode::Pairs | SudiceCode::Longest | SudiceCode::Unique |
+            SudiceCode::Jump(_) | SudiceCode::Label(_) => 0,
+            SudiceCode::Tuple(n) => 1 - n as isize,
+            _ => -1,
+        }
+    }
+}
+
+// A byte range [start, end) into the source text of an expression.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SudiceSpan {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl SudiceSpan {
+    pub fn new(start: usize, end: usize) -> SudiceSpan {
+        SudiceSpan { start, end }
+    }
+
+    pub fn excerpt<'a>(&self, source: &'a str) -> &'a str {
+        &source[self.start..self.end]
+    }
+}
+
+// Compiled bytecode. spans[i] is the source text which produced code[i];
+// for operators this covers the whole subexpression, operands included.
+// `slots` is the number of slots the lets in it bind, one past the highest.
 #[derive(Debug)]
 pub struct SudiceExpression {
+    pub source: String,
     pub code: Vec<SudiceCode>,
+    pub spans: Vec<SudiceSpan>,
+    pub slots: usize,
+}
+
+#[derive(Debug)]
+pub struct SudiceError {
+    pub span: SudiceSpan,
+    pub message: String,
+}
+
+impl SudiceError {
+    pub fn new<S: Into<String>>(span: SudiceSpan, message: S) -> SudiceError {
+        SudiceError { span, message: message.into() }
+    }
+}
+
+impl fmt::Display for SudiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
 }
@@ -0,0 +1,80 @@
+use descriptor::{SudiceCode, SudiceExpression};
+
+use std::cmp;
+use std::fmt;
+use std::vec::Vec;
+
+pub struct Disassembly<'a> {
+    code: &'a SudiceExpression,
+    depths: Vec<usize>,
+}
+
+// Computes the stack depth after each instruction, counting the top of
+// stack. Every branch of a select starts at the depth the select left
+// behind, which is what the predicate was popped down to.
+fn stack_depths(d: &SudiceExpression) -> Vec<usize> {
+    let mut entry: Vec<Option<usize>> = vec![None; d.code.len() + 1];
+    let mut depths = Vec::with_capacity(d.code.len());
+    let mut depth: isize = 0;
+    for (i, code) in d.code.iter().enumerate() {
+        if let Some(e) = entry[i] {
+            depth = e as isize;
+        }
+        depth += code.stack_effect();
+        if let SudiceCode::Select(ref offsets) = *code {
+            for t in branch_targets(i, offsets) {
+                entry[t] = Some(depth as usize);
+            }
+        }
+        depths.push(depth as usize);
+    }
+    depths
+}
+
+fn branch_targets(dcp: usize, offsets: &[usize]) -> Vec<usize> {
+    let len = offsets.len();
+    let mut targets = vec![dcp + 1];
+    for offset in &offsets[..len-1] {
+        targets.push(dcp + offset + 1);
+    }
+    targets
+}
+
+pub fn disassemble(d: &SudiceExpression) -> Disassembly<'_> {
+    Disassembly { code: d, depths: stack_depths(d) }
+}
+
+impl<'a> fmt::Display for Disassembly<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let d = self.code;
+        let ichars = index_width(d.code.len());
+        writeln!(f, "{:>width$} {:>5}  {:<32} SOURCE", "IDX", "DEPTH", "INSTRUCTION", width = ichars)?;
+        for (i, code) in d.code.iter().enumerate() {
+            let instr = match *code {
+                SudiceCode::Jump(offset) => format!("Jump -> {}", i + offset + 1),
+                SudiceCode::BestOf(offset) => format!("BestOf <- {}", i - offset),
+                SudiceCode::WorstOf(offset) => format!("WorstOf <- {}", i - offset),
+                SudiceCode::SumOf(offset) => format!("SumOf <- {}", i - offset),
+                SudiceCode::CollectOf(offset) => format!("CollectOf <- {}", i - offset),
+                SudiceCode::Bind(slot, len) => format!("Bind {} until {}", slot, i + len + 1),
+                SudiceCode::Select(ref offsets) => {
+                    let targets = branch_targets(i, offsets);
+                    let mut s = "Select".to_string();
+                    for (n, t) in targets[..targets.len()-1].iter().enumerate() {
+                        s.push_str(&format!(" {}:{}", n + 1, t));
+                    }
+                    s.push_str(&format!(" _:{} end:{}", targets[targets.len()-1], i + offsets[offsets.len()-1] + 1));
+                    s
+                },
+                ref c => format!("{:?}", c),
+            };
+            let excerpt = d.spans[i].excerpt(&d.source);
+            writeln!(f, "{:>width$} {:>5}  {:<32} {}", i, self.depths[i], instr, excerpt, width = ichars)?;
+        }
+        Ok(())
+    }
+}
+
+fn index_width(len: usize) -> usize {
+    cmp::max(3, len.to_string().len())
+}
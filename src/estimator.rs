@@ -1,22 +1,52 @@
-use descriptor::SudiceExpression;
-use interpreter;
+use checker::Support;
+use descriptor::{SudiceCode, SudiceExpression};
+use interpreter::Interpreter;
 
-use rand;
+use rand::{self, Rng};
 
+use std::slice;
 use std::vec::Vec;
 use std::fmt;
 
 const OBS_FACTOR: usize = 2000;
 
+// Samples failing a given clause are made up for by drawing more, but no
+// more than this many times as many as there were meant to be observations.
+// A condition holding less often than once in this many draws leaves too
+// few observations for the results to be relied upon.
+const REJECTION_FACTOR: usize = 100;
+
+// Tuples want observations for every combination of their components'
+// values, which multiply quickly, so no more than this many are drawn.
+const JOINT_OBS_CAP: usize = 1 << 21;
+
 #[derive(Debug)]
 pub struct SudiceResults {
     pub total: usize,
     pub min: i64,
     pub max: i64,
+    // Every outcome the checker found reachable, in increasing order, with
+    // hist and dist giving the count and proportion observed of each.
+    pub values: Vec<i64>,
     pub hist: Vec<u64>,
     pub dist: Vec<f64>,
     pub ev: f64,
     pub sd: f64,
+    // Every sample drawn, counting those which failed a given clause and so
+    // are not among the observations.
+    pub attempts: usize,
+    // One entry for every label given to a select branch, in the order they
+    // appear in the expression.
+    pub labels: Vec<LabelResults>,
+}
+
+// The share of observations which came out of a select branch with this
+// label, and the expected value of those observations alone.
+#[derive(Debug)]
+pub struct LabelResults {
+    pub label: String,
+    pub share: f64,
+    pub ev: f64,
 }
 
 impl fmt::Display for SudiceResults {
@@ -25,49 +55,308 @@ impl fmt::Display for SudiceResults {
         writeln!(f, "Minimum:\t{}", self.min)?;
         writeln!(f, "Maximum:\t{}", self.max)?;
         writeln!(f, "Range:\t\t{}", self.max - self.min)?;
+        writeln!(f, "Outcomes:\t{}", self.values.len())?;
         writeln!(f, "Observations:\t{}", self.total)?;
+        write_acceptance(f, self.total, self.attempts)?;
+        if self.total == 0 {
+            return Ok(());
+        }
         writeln!(f, "Expected Value:\t{}", self.ev)?;
         writeln!(f, "Std. Deviation:\t{}", self.sd)?;
         writeln!(f, "##### [ DISTRIBUTION ] #####")?;
         let ichars = 1 + ((self.max.abs() + 1) as f64).log10().ceil() as usize;
         let fchars = (self.total as f64).log10().ceil() as usize; 
         let mhist = *(self.hist.iter().max().unwrap()) as f64;
-        for i in self.min..self.max+1 {
-            let idx = (i - self.min) as usize;
+        for (idx, &i) in self.values.iter().enumerate() {
             let dprop = (self.hist[idx] as f64) / mhist;
             write!(f, "{n:>width$} |", n = i, width = ichars)?;
             write!(f, "{freq:>width$} ", freq = self.hist[idx] as usize, width = fchars)?;
             writeln!(f, "| {prop:>width$}%", prop = (self.dist[idx] * 100.0).round(), width = (20.0 * dprop).round() as usize)?;
         }
+        if !self.labels.is_empty() {
+            writeln!(f, "##### [ LABELS ] #####")?;
+            for l in &self.labels {
+                writeln!(f, "{}:\t{:.2}%\tEV {:.4}", l.label, l.share * 100.0, l.ev)?;
+            }
+        }
         Ok(())
     }
 }
 
-pub fn estimate(code: &SudiceExpression, min: i64, max: i64) -> SudiceResults {
-    let size = (max - min + 1) as usize;
-    let mut hist: Vec<u64> = Vec::with_capacity(size);
-    hist.resize(size, 0);
-    let mut rng = rand::thread_rng();
-    let total = size * OBS_FACTOR;
-    for _ in 0..total {
-        let s = interpreter::interpret(&code, &mut rng);
-        hist[(s - min) as usize] += 1;
-    }
-
-    // Compute exp. value and cache distribution
-    let mut dist: Vec<f64> = Vec::with_capacity(size);
-    let mut ev = 0.0 as f64;
-    for i in min..max+1 {
-        let div = (hist[(i - min) as usize] as f64) / (total as f64);
-        dist.push(div);
-        ev += i as f64 * div;
-    }
-
-    // Compute std. dev
-    let mut sd = 0.0 as f64;
-    for i in min..max+1 {
-        sd += (i as f64 - ev) * (i as f64 - ev) * dist[(i - min) as usize];
-    }
-    sd = sd.sqrt();
-    SudiceResults { total: total, min: min, max: max, hist: hist, dist: dist, ev: ev, sd: sd }
+// Reports the share of samples which met a given clause, if any failed it.
+fn write_acceptance(f: &mut fmt::Formatter, total: usize, attempts: usize) -> fmt::Result {
+    if attempts > total {
+        writeln!(f, "Acceptance:\t{:.2}%", total as f64 / attempts as f64 * 100.0)?;
+    }
+    if unreliable(total, attempts) {
+        writeln!(f, "[Warning] Too few samples met the condition for these results to be reliable.")?;
+    }
+    Ok(())
+}
+
+fn unreliable(total: usize, attempts: usize) -> bool {
+    total * REJECTION_FACTOR < attempts
+}
+
+impl SudiceResults {
+    // Results for a histogram over every outcome the checker found
+    // reachable, in increasing order, counting every sample as observed.
+    pub fn from_hist(values: Vec<i64>, hist: Vec<u64>, labels: Vec<LabelResults>) -> SudiceResults {
+        let total = hist.iter().sum::<u64>() as usize;
+
+        // Compute exp. value and cache distribution
+        let mut dist: Vec<f64> = Vec::with_capacity(values.len());
+        let mut ev = 0.0f64;
+        for (idx, &i) in values.iter().enumerate() {
+            let div = (hist[idx] as f64) / (total as f64);
+            dist.push(div);
+            ev += i as f64 * div;
+        }
+
+        // Compute std. dev
+        let mut sd = 0.0f64;
+        for (idx, &i) in values.iter().enumerate() {
+            sd += (i as f64 - ev) * (i as f64 - ev) * dist[idx];
+        }
+        sd = sd.sqrt();
+        let (min, max) = (values[0], values[values.len()-1]);
+        SudiceResults { total, min, max, values, hist, dist, ev, sd, attempts: total, labels }
+    }
+
+    // The share of samples drawn which met the expression's condition.
+    pub fn acceptance(&self) -> f64 {
+        self.total as f64 / self.attempts as f64
+    }
+
+    pub fn unreliable(&self) -> bool {
+        unreliable(self.total, self.attempts)
+    }
+
+    // The proportion of observations which came out as `x`.
+    pub fn proportion(&self, x: i64) -> f64 {
+        self.values.binary_search(&x).ok().map_or(0.0, |idx| self.dist[idx])
+    }
+}
+
+// Results of a boolean expression, which are shown as the chance that it
+// holds rather than as a histogram of the 1s and 2s standing for true and
+// false.
+pub struct Truth<'a>(pub &'a SudiceResults);
+
+impl<'a> fmt::Display for Truth<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let p = self.0.proportion(1);
+        writeln!(f, "##### [ RESULTS ] #####")?;
+        writeln!(f, "Observations:\t{}", self.0.total)?;
+        write_acceptance(f, self.0.total, self.0.attempts)?;
+        if self.0.total == 0 {
+            return Ok(());
+        }
+        writeln!(f, "P(true):\t{:.2}%", p * 100.0)?;
+        writeln!(f, "P(false):\t{:.2}%", (1.0 - p) * 100.0)
+    }
+}
+
+// The distinct labels of an expression in the order they appear, and which
+// of them the label at each position of the bytecode is, if it is one.
+fn label_slots(code: &SudiceExpression) -> (Vec<String>, Vec<Option<usize>>) {
+    let mut at: Vec<(usize, &str)> = code.code.iter().enumerate().filter_map(|(i, c)| match *c {
+        SudiceCode::Label(ref name) => Some((code.spans[i].start, name.as_str())),
+        _ => None,
+    }).collect();
+    at.sort_unstable();
+    let mut names: Vec<String> = Vec::new();
+    for (_, name) in at {
+        if !names.iter().any(|n| n == name) {
+            names.push(name.to_string());
+        }
+    }
+    let slots = code.code.iter().map(|c| match *c {
+        SudiceCode::Label(ref name) => names.iter().position(|n| n == name),
+        _ => None,
+    }).collect();
+    (names, slots)
+}
+
+pub fn estimate(code: &SudiceExpression, support: &Support) -> SudiceResults {
+    estimate_with(code, support, &mut rand::thread_rng())
+}
+
+pub fn estimate_with<R: Rng>(code: &SudiceExpression, support: &Support, rng: &mut R) -> SudiceResults {
+    let values = support.values();
+    let size = values.len();
+    let mut hist: Vec<u64> = vec![0; size];
+    let mut interpreter = Interpreter::new(code);
+    let (names, slots) = label_slots(code);
+    let mut counts = vec![0u64; names.len()];
+    let mut sums = vec![0.0f64; names.len()];
+    let wanted = size * OBS_FACTOR;
+    let (mut total, mut attempts) = (0, 0);
+    while total < wanted && attempts < wanted * REJECTION_FACTOR {
+        attempts += 1;
+        let s = interpreter.sample(rng);
+        if interpreter.rejected() {
+            continue;
+        }
+        total += 1;
+        match support.index(s) {
+            Some(idx) => hist[idx] += 1,
+            None => panic!("Sampled {}, which the checker found unreachable.", s),
+        }
+        if let Some(slot) = interpreter.label().and_then(|at| slots[at]) {
+            counts[slot] += 1;
+            sums[slot] += s as f64;
+        }
+    }
+    let labels = names.into_iter().enumerate().map(|(i, label)| LabelResults {
+        label,
+        share: if total > 0 { counts[i] as f64 / total as f64 } else { 0.0 },
+        ev: if counts[i] > 0 { sums[i] / counts[i] as f64 } else { 0.0 },
+    }).collect();
+    let mut results = SudiceResults::from_hist(values, hist, labels);
+    results.attempts = attempts;
+    results
+}
+
+// Results of an expression ending in a tuple, kept as one histogram over
+// every combination of its components' values, so that how they relate is
+// not lost. The components are numbered from 1 when shown.
+#[derive(Debug)]
+pub struct JointResults {
+    pub total: usize,
+    pub attempts: usize,
+    // Every outcome the checker found reachable for each component, in
+    // increasing order.
+    pub values: Vec<Vec<i64>>,
+    // The count observed of each combination, with the outcome of the
+    // first component varying slowest and of the last fastest.
+    pub hist: Vec<u64>,
+    // Whether fewer observations were drawn than the combinations wanted.
+    pub capped: bool,
+}
+
+impl JointResults {
+    // The position among its own outcomes of component `i` of the
+    // combination at `idx` in the histogram.
+    fn digit(&self, idx: usize, i: usize) -> usize {
+        let stride: usize = self.values[i + 1..].iter().map(|v| v.len()).product();
+        idx / stride % self.values[i].len()
+    }
+
+    // The outcomes of every combination observed at least once, with its
+    // count.
+    pub fn outcomes(&self) -> Vec<(Vec<i64>, u64)> {
+        self.hist.iter().enumerate().filter(|&(_, &n)| n > 0).map(|(idx, &n)| {
+            ((0..self.values.len()).map(|i| self.values[i][self.digit(idx, i)]).collect(), n)
+        }).collect()
+    }
+
+    // Results for component `i` alone, whatever the others came out as.
+    pub fn marginal(&self, i: usize) -> SudiceResults {
+        let mut hist = vec![0u64; self.values[i].len()];
+        for (idx, &n) in self.hist.iter().enumerate() {
+            hist[self.digit(idx, i)] += n;
+        }
+        let mut results = SudiceResults::from_hist(self.values[i].clone(), hist, Vec::new());
+        results.attempts = self.attempts;
+        results
+    }
+
+    // Results for component `i` over only the observations in which
+    // component `j` came out as `x`, or None if none did.
+    pub fn conditional(&self, i: usize, j: usize, x: i64) -> Option<SudiceResults> {
+        let at = self.values[j].binary_search(&x).ok()?;
+        let mut hist = vec![0u64; self.values[i].len()];
+        for (idx, &n) in self.hist.iter().enumerate() {
+            if self.digit(idx, j) == at {
+                hist[self.digit(idx, i)] += n;
+            }
+        }
+        if hist.iter().all(|&n| n == 0) {
+            return None;
+        }
+        Some(SudiceResults::from_hist(self.values[i].clone(), hist, Vec::new()))
+    }
+}
+
+impl fmt::Display for JointResults {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let n = self.values.len();
+        writeln!(f, "##### [ JOINT RESULTS ] #####")?;
+        writeln!(f, "Components:\t{}", n)?;
+        writeln!(f, "Observations:\t{}", self.total)?;
+        if self.capped {
+            writeln!(f, "[Warning] Observations were capped at {}, too few to see every combination reliably.", JOINT_OBS_CAP)?;
+        }
+        write_acceptance(f, self.total, self.attempts)?;
+        if self.total == 0 {
+            return Ok(());
+        }
+        for i in 0..n {
+            let m = self.marginal(i);
+            writeln!(f, "X{}:\t\tEV {:.4}\tSD {:.4}", i + 1, m.ev, m.sd)?;
+        }
+        writeln!(f, "##### [ JOINT DISTRIBUTION ] #####")?;
+        let outcomes = self.outcomes();
+        let names: Vec<String> = outcomes.iter().map(|(v, _)| {
+            format!("({})", v.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", "))
+        }).collect();
+        let ichars = names.iter().map(|s| s.len()).max().unwrap_or(1);
+        let fchars = (self.total as f64).log10().ceil() as usize;
+        let mhist = outcomes.iter().map(|&(_, n)| n).max().unwrap_or(1) as f64;
+        for (name, &(_, count)) in names.iter().zip(outcomes.iter()) {
+            let dprop = count as f64 / mhist;
+            write!(f, "{n:>width$} |", n = name, width = ichars)?;
+            write!(f, "{freq:>width$} ", freq = count, width = fchars)?;
+            let prop = (count as f64 / self.total as f64 * 100.0).round();
+            writeln!(f, "| {prop:>width$}%", prop = prop, width = (20.0 * dprop).round() as usize)?;
+        }
+        // How every other component fares depending on how the first came
+        // out.
+        writeln!(f, "##### [ GIVEN X1 ] #####")?;
+        let first = self.marginal(0);
+        for (idx, &x) in first.values.iter().enumerate().filter(|&(idx, _)| first.hist[idx] > 0) {
+            write!(f, "X1 = {}:\t{:.2}%", x, first.dist[idx] * 100.0)?;
+            for i in 1..n {
+                let c = self.conditional(i, 0, x).unwrap();
+                write!(f, "\tE[X{}] {:.4}", i + 1, c.ev)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn estimate_joint(code: &SudiceExpression, supports: &[Support]) -> JointResults {
+    estimate_joint_with(code, supports, &mut rand::thread_rng())
+}
+
+// Estimates an expression which has passed the joint check, whether or not
+// it ends in a tuple.
+pub fn estimate_joint_with<R: Rng>(code: &SudiceExpression, supports: &[Support], rng: &mut R) -> JointResults {
+    let values: Vec<Vec<i64>> = supports.iter().map(Support::values).collect();
+    let size: usize = values.iter().map(|v| v.len()).product();
+    let mut hist: Vec<u64> = vec![0; size];
+    let mut interpreter = Interpreter::new(code);
+    let capped = size.saturating_mul(OBS_FACTOR) > JOINT_OBS_CAP;
+    let wanted = if capped { JOINT_OBS_CAP } else { size * OBS_FACTOR };
+    let (mut total, mut attempts) = (0, 0);
+    while total < wanted && attempts < wanted * REJECTION_FACTOR {
+        attempts += 1;
+        let s = interpreter.sample(rng);
+        if interpreter.rejected() {
+            continue;
+        }
+        total += 1;
+        let outcome = if supports.len() > 1 { interpreter.components() } else { slice::from_ref(&s) };
+        let mut idx = 0;
+        for (&x, support) in outcome.iter().zip(supports) {
+            match support.index(x) {
+                Some(i) => idx = idx * support.len() as usize + i,
+                None => panic!("Sampled {}, which the checker found unreachable.", x),
+            }
+        }
+        hist[idx] += 1;
+    }
+    JointResults { total, attempts, values, hist, capped }
 }
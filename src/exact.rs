@@ -0,0 +1,385 @@
+use descriptor::{self, from_bool, SudiceCode, SudiceExpression, REROLL_DEPTH};
+
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::vec::Vec;
+
+// A value as the interpreter sees it, except that the dice of a roll are
+// kept sorted so that rolls differing only in order are the same outcome.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum ExactValue {
+    Scalar(i64),
+    Dice(i64, Vec<i64>),
+}
+
+impl ExactValue {
+    fn sum(&self) -> i64 {
+        match *self {
+            ExactValue::Scalar(i) => i,
+            ExactValue::Dice(_, ref v) => v.iter().fold(0, |acc: i64, &x| acc.saturating_add(x)),
+        }
+    }
+}
+
+type Dist = BTreeMap<ExactValue, f64>;
+
+fn point(value: ExactValue) -> Dist {
+    let mut dist = Dist::new();
+    dist.insert(value, 1.0);
+    dist
+}
+
+// Computes exact distributions by brute force, independently of the
+// interpreter: every roll is expanded into each way its dice could land,
+// and every operator is applied to each pair of outcomes of its operands.
+// Gives up once any distribution has more than `limit` outcomes. Tests use
+// this as a reference to hold the sampler to.
+//
+// Operands are only independent of each other until a name is used twice,
+// so the body of a let is worked out separately for each value it binds,
+// which `vars` holds while it is.
+struct Exact<'a> {
+    d: &'a SudiceExpression,
+    limit: usize,
+    vars: RefCell<Vec<i64>>,
+}
+
+macro_rules! add {
+    ($dist:expr, $value:expr, $p:expr) => {
+        *$dist.entry($value).or_insert(0.0) += $p
+    }
+}
+
+impl<'a> Exact<'a> {
+    fn bounded(&self, dist: Dist) -> Option<Dist> {
+        if dist.len() > self.limit { None } else { Some(dist) }
+    }
+
+    fn map<F: Fn(&ExactValue) -> ExactValue>(&self, a: &Dist, f: F) -> Dist {
+        let mut dist = Dist::new();
+        for (x, p) in a {
+            add!(dist, f(x), p);
+        }
+        dist
+    }
+
+    // Operands come from disjoint dice, so they are independent.
+    fn combine<F: Fn(&ExactValue, &ExactValue) -> ExactValue>(&self, a: &Dist, b: &Dist, f: F) -> Option<Dist> {
+        if a.len() * b.len() > self.limit * self.limit {
+            return None;
+        }
+        let mut dist = Dist::new();
+        for (x, p) in a {
+            for (y, q) in b {
+                add!(dist, f(x, y), p * q);
+            }
+        }
+        self.bounded(dist)
+    }
+
+    fn scalars<F: Fn(i64, i64) -> i64>(&self, a: &Dist, b: &Dist, f: F) -> Option<Dist> {
+        self.combine(a, b, |x, y| ExactValue::Scalar(f(x.sum(), y.sum())))
+    }
+
+    // Rolls `n` more dice of size `x` alongside those already `kept`.
+    fn roll(&self, n: i64, x: i64, kept: &[i64]) -> Option<Dist> {
+        let mut dist = point(ExactValue::Dice(x, kept.to_vec()));
+        for _ in 0..n {
+            let mut next = Dist::new();
+            for (v, p) in &dist {
+                if let ExactValue::Dice(_, ref v) = *v {
+                    for face in 1..=x {
+                        let mut w = v.clone();
+                        let at = w.binary_search(&face).unwrap_or_else(|i| i);
+                        w.insert(at, face);
+                        add!(next, ExactValue::Dice(x, w), p / x as f64);
+                    }
+                }
+            }
+            dist = self.bounded(next)?;
+        }
+        Some(dist)
+    }
+
+    // Replaces each outcome of `a` with a distribution of its own.
+    fn expand<F: Fn(&ExactValue) -> Option<Dist>>(&self, a: &Dist, f: F) -> Option<Dist> {
+        let mut dist = Dist::new();
+        for (x, p) in a {
+            for (y, q) in f(x)? {
+                add!(dist, y, p * q);
+            }
+        }
+        self.bounded(dist)
+    }
+
+    // Rerolls the dice of `tos` which `pick` chooses, given their sorted
+    // faces and the operand.
+    fn reroll<F: Fn(&[i64], i64) -> Vec<bool>>(&self, tos: &Dist, n: &Dist, pick: F) -> Option<Dist> {
+        let mut dist = Dist::new();
+        for (v, p) in tos {
+            for (m, q) in n {
+                if let ExactValue::Dice(x, ref v) = *v {
+                    let picked = pick(v, m.sum());
+                    let kept: Vec<i64> = v.iter().zip(picked.iter()).filter(|&(_, &r)| !r).map(|(&f, _)| f).collect();
+                    let rerolled = (v.len() - kept.len()) as i64;
+                    for (y, r) in self.roll(rerolled, x, &kept)? {
+                        add!(dist, y, p * q * r);
+                    }
+                }
+            }
+        }
+        self.bounded(dist)
+    }
+
+    // Rerolls the dice of `tos` which `pick` chooses, round after round, up
+    // to REROLL_DEPTH rounds. The operand must stay the same throughout, so
+    // each of its outcomes is worked through separately.
+    fn reroll_until<F: Fn(&[i64], i64) -> Vec<bool>>(&self, tos: &Dist, n: &Dist, pick: F) -> Option<Dist> {
+        self.expand(n, |m| {
+            let m = point(ExactValue::Scalar(m.sum()));
+            let mut dist = tos.clone();
+            for _ in 0..REROLL_DEPTH {
+                let next = self.reroll(&dist, &m, &pick)?;
+                if next == dist {
+                    break;
+                }
+                dist = next;
+            }
+            Some(dist)
+        })
+    }
+
+    // The best (or worst) of `k` independent runs: the greatest of them is
+    // at most s exactly when every one of them is.
+    fn repeat(&self, a: &Dist, k: i64, best: bool) -> Dist {
+        let mut sums = BTreeMap::new();
+        for (x, p) in a {
+            add!(sums, x.sum(), p);
+        }
+        let mut dist = Dist::new();
+        let mut below = 0.0f64;
+        let ordered: Vec<(i64, f64)> = if best {
+            sums.into_iter().collect()
+        } else {
+            sums.into_iter().rev().collect()
+        };
+        for (s, p) in ordered {
+            let p_extreme = (below + p).powi(k as i32) - below.powi(k as i32);
+            add!(dist, ExactValue::Scalar(s), p_extreme);
+            below += p;
+        }
+        dist
+    }
+
+    // The sum of `k` independent runs, or else the runs themselves, kept
+    // sorted as the dice of a roll are.
+    fn runs(&self, a: &Dist, k: i64, collect: bool) -> Option<Dist> {
+        let mut sums = BTreeMap::new();
+        for (x, p) in a {
+            add!(sums, x.sum(), p);
+        }
+        let mut dist = point(if collect { ExactValue::Dice(0, Vec::new()) } else { ExactValue::Scalar(0) });
+        for _ in 0..k {
+            let mut next = Dist::new();
+            for (v, p) in &dist {
+                for (&s, &q) in &sums {
+                    let w = match *v {
+                        ExactValue::Scalar(t) => ExactValue::Scalar(t.saturating_add(s)),
+                        ExactValue::Dice(size, ref v) => {
+                            let mut w = v.clone();
+                            let at = w.binary_search(&s).unwrap_or_else(|i| i);
+                            w.insert(at, s);
+                            ExactValue::Dice(size, w)
+                        },
+                    };
+                    add!(next, w, p * q);
+                }
+            }
+            dist = self.bounded(next)?;
+        }
+        Some(dist)
+    }
+
+    fn eval(&self, start: usize, end: usize, until_jump: bool, stack: &mut Vec<Dist>) -> Option<()> {
+        let d = self.d;
+        let mut dcp = start;
+        macro_rules! op2 {
+            ($f:expr) => {{
+                let tos = stack.pop().unwrap();
+                let below = stack.pop().unwrap();
+                stack.push($f(&tos, &below)?);
+            }}
+        }
+        while dcp < end {
+            match d.code[dcp] {
+                SudiceCode::Num(i) => stack.push(point(ExactValue::Scalar(i))),
+                SudiceCode::Bool(b) => stack.push(point(ExactValue::Scalar(from_bool(b)))),
+                SudiceCode::RollConst(n, x) => stack.push(self.roll(n, x, &[])?),
+                SudiceCode::Add => op2!(|a, b| self.scalars(a, b, i64::saturating_add)),
+                SudiceCode::Sub => op2!(|a, b| self.scalars(a, b, i64::saturating_sub)),
+                SudiceCode::Mul => op2!(|a, b| self.scalars(a, b, i64::saturating_mul)),
+                SudiceCode::Div => op2!(|a, b| self.scalars(a, b, |x, y| descriptor::checked_div_floor(x, y).unwrap_or(i64::MAX))),
+                SudiceCode::DivCeil => op2!(|a, b| self.scalars(a, b, |x, y| descriptor::checked_div_ceil(x, y).unwrap_or(i64::MAX))),
+                SudiceCode::DivRound => op2!(|a, b| self.scalars(a, b, |x, y| descriptor::checked_div_round(x, y).unwrap_or(i64::MAX))),
+                SudiceCode::Mod => op2!(|a, b| self.scalars(a, b, |x, y| descriptor::checked_rem_floor(x, y).unwrap_or(0))),
+                SudiceCode::Pow => op2!(|a, b| self.scalars(a, b, descriptor::saturating_pow)),
+                SudiceCode::Min => op2!(|a, b| self.scalars(a, b, cmp::min)),
+                SudiceCode::Max => op2!(|a, b| self.scalars(a, b, cmp::max)),
+                SudiceCode::Roll => op2!(|a: &Dist, b: &Dist| self.expand(a, |n| {
+                    self.expand(b, |x| self.roll(n.sum(), x.sum(), &[]))
+                })),
+                SudiceCode::Reroll => op2!(|a, b| self.reroll(a, b, |v, n| v.iter().map(|&f| f == n).collect())),
+                SudiceCode::RerollLowest => op2!(|a, b| self.reroll(a, b, |v, n| (0..v.len()).map(|i| (i as i64) < n).collect())),
+                SudiceCode::RerollHighest => op2!(|a, b| self.reroll(a, b, |v, n| (0..v.len()).map(|i| (v.len() - i) as i64 <= n).collect())),
+                SudiceCode::RerollBelow => op2!(|a, b| self.reroll(a, b, |v, n| v.iter().map(|&f| f < n).collect())),
+                SudiceCode::RerollAbove => op2!(|a, b| self.reroll(a, b, |v, n| v.iter().map(|&f| f > n).collect())),
+                SudiceCode::RerollUntil => op2!(|a, b| self.reroll_until(a, b, |v, n| v.iter().map(|&f| f == n).collect())),
+                SudiceCode::RerollBelowUntil => op2!(|a, b| self.reroll_until(a, b, |v, n| v.iter().map(|&f| f < n).collect())),
+                SudiceCode::RerollAboveUntil => op2!(|a, b| self.reroll_until(a, b, |v, n| v.iter().map(|&f| f > n).collect())),
+                SudiceCode::DropLowest => op2!(|a, b| self.combine(a, b, |x, n| match *x {
+                    ExactValue::Dice(size, ref v) => ExactValue::Dice(size, v[n.sum() as usize..].to_vec()),
+                    ExactValue::Scalar(_) => unreachable!(),
+                })),
+                SudiceCode::DropHighest => op2!(|a, b| self.combine(a, b, |x, n| match *x {
+                    ExactValue::Dice(size, ref v) => ExactValue::Dice(size, v[..v.len() - n.sum() as usize].to_vec()),
+                    ExactValue::Scalar(_) => unreachable!(),
+                })),
+                SudiceCode::Ceil => op2!(|a, b| self.combine(a, b, |x, n| match *x {
+                    ExactValue::Dice(size, ref v) => ExactValue::Dice(size, v.iter().map(|&f| cmp::min(f, n.sum())).collect()),
+                    ExactValue::Scalar(s) => ExactValue::Scalar(cmp::min(s, n.sum())),
+                })),
+                SudiceCode::Floor => op2!(|a, b| self.combine(a, b, |x, n| match *x {
+                    ExactValue::Dice(size, ref v) => ExactValue::Dice(size, v.iter().map(|&f| cmp::max(f, n.sum())).collect()),
+                    ExactValue::Scalar(s) => ExactValue::Scalar(cmp::max(s, n.sum())),
+                })),
+                // A single run is passed on as it is, dice and all.
+                SudiceCode::BestOf(_) | SudiceCode::WorstOf(_) => {
+                    let best = matches!(d.code[dcp], SudiceCode::BestOf(_));
+                    op2!(|a: &Dist, b: &Dist| self.expand(b, |k| Some(if k.sum() > 1 { self.repeat(a, k.sum(), best) } else { a.clone() })))
+                },
+                SudiceCode::SumOf(_) | SudiceCode::CollectOf(_) => {
+                    let collect = matches!(d.code[dcp], SudiceCode::CollectOf(_));
+                    op2!(|a: &Dist, b: &Dist| self.expand(b, |k| self.runs(a, cmp::max(k.sum(), 1), collect)))
+                },
+                SudiceCode::Select(ref offsets) => {
+                    let len = offsets.len();
+                    let pred = stack.pop().unwrap();
+                    let mut starts = vec![dcp + 1];
+                    starts.extend(offsets[..len-1].iter().map(|offset| dcp + offset + 1));
+                    // A predicate of 1 selects the first branch, 2 through
+                    // len-1 the rest in order, and anything else the default.
+                    // Only branches which can be selected are evaluated, as
+                    // the checker has not vouched for any others.
+                    let pick = |t: &ExactValue| match t.sum() {
+                        t if t >= 1 && t < len as i64 => t as usize - 1,
+                        _ => len - 1,
+                    };
+                    let mut branches: Vec<Option<Dist>> = vec![None; len];
+                    for t in pred.keys() {
+                        let i = pick(t);
+                        if branches[i].is_none() {
+                            self.eval(starts[i], d.code.len(), true, stack)?;
+                            branches[i] = stack.pop();
+                        }
+                    }
+                    let result = self.expand(&pred, |t| branches[pick(t)].clone())?;
+                    stack.push(result);
+                    dcp += offsets[len-1];
+                },
+                SudiceCode::Label(_) => {},
+                SudiceCode::Bind(slot, len) => {
+                    let bound = stack.pop().unwrap();
+                    let mut sums = BTreeMap::new();
+                    for (x, p) in &bound {
+                        add!(sums, x.sum(), p);
+                    }
+                    let mut dist = Dist::new();
+                    for (x, p) in sums {
+                        self.vars.borrow_mut()[slot] = x;
+                        self.eval(dcp + 1, dcp + len + 1, false, stack)?;
+                        for (y, q) in stack.pop().unwrap() {
+                            add!(dist, y, p * q);
+                        }
+                    }
+                    stack.push(self.bounded(dist)?);
+                    dcp += len;
+                },
+                SudiceCode::Load(slot) => stack.push(point(ExactValue::Scalar(self.vars.borrow()[slot]))),
+                // Only single values are enumerated.
+                SudiceCode::Tuple(_) => return None,
+                // Outcomes are weighted by the chance that the condition
+                // holds alongside them, and renormalized once at the end.
+                // Only names tie the two together, and each of those holds
+                // a single value here.
+                SudiceCode::Given => {
+                    let value = stack.pop().unwrap();
+                    let cond = stack.pop().unwrap();
+                    let holds: f64 = cond.iter().filter(|&(x, _)| x.sum() == 1).map(|(_, p)| p).sum();
+                    stack.push(value.into_iter().map(|(x, p)| (x, p * holds)).collect());
+                },
+                SudiceCode::Jump(_) => if until_jump {
+                    return Some(());
+                } else {
+                    panic!("Error: Illegal bytecode sequence: Should not reach jump!");
+                },
+                SudiceCode::Lt => op2!(|a, b| self.scalars(a, b, |x, y| from_bool(x < y))),
+                SudiceCode::Gt => op2!(|a, b| self.scalars(a, b, |x, y| from_bool(x > y))),
+                SudiceCode::Eq => op2!(|a, b| self.scalars(a, b, |x, y| from_bool(x == y))),
+                SudiceCode::Ne => op2!(|a, b| self.scalars(a, b, |x, y| from_bool(x != y))),
+                SudiceCode::And => op2!(|a, b| self.scalars(a, b, |x, y| from_bool(x == 1 && y == 1))),
+                SudiceCode::Or => op2!(|a, b| self.scalars(a, b, |x, y| from_bool(x == 1 || y == 1))),
+                SudiceCode::Xor => op2!(|a, b| self.scalars(a, b, |x, y| from_bool((x == 1) != (y == 1)))),
+                SudiceCode::Not | SudiceCode::Count | SudiceCode::Abs | SudiceCode::Neg => {
+                    let a = stack.pop().unwrap();
+                    let f: fn(i64) -> i64 = match d.code[dcp] {
+                        SudiceCode::Not => |x| from_bool(x != 1),
+                        SudiceCode::Count => |x| (x == 1) as i64,
+                        SudiceCode::Abs => i64::saturating_abs,
+                        _ => i64::saturating_neg,
+                    };
+                    stack.push(self.map(&a, |x| ExactValue::Scalar(f(x.sum()))));
+                },
+                SudiceCode::Largest | SudiceCode::Pairs | SudiceCode::Longest | SudiceCode::Unique => {
+                    let a = stack.pop().unwrap();
+                    let f: fn(&[i64]) -> i64 = match d.code[dcp] {
+                        SudiceCode::Largest => descriptor::largest,
+                        SudiceCode::Pairs => descriptor::pairs,
+                        SudiceCode::Longest => descriptor::longest,
+                        _ => descriptor::unique,
+                    };
+                    stack.push(self.map(&a, |x| match *x {
+                        ExactValue::Dice(_, ref v) => ExactValue::Scalar(f(v)),
+                        ExactValue::Scalar(_) => unreachable!(),
+                    }));
+                },
+                SudiceCode::Highest => op2!(|a, b| self.combine(a, b, |x, k| match *x {
+                    ExactValue::Dice(_, ref v) => ExactValue::Scalar(descriptor::highest(v, k.sum())),
+                    ExactValue::Scalar(_) => unreachable!(),
+                })),
+            }
+            dcp += 1;
+        }
+        Some(())
+    }
+}
+
+// The exact probability of each outcome of an expression which has passed
+// the semantic check, in increasing order, or None if the expression has
+// too many ways to turn out for that to be worked out by brute force.
+pub fn distribution(d: &SudiceExpression, limit: usize) -> Option<Vec<(i64, f64)>> {
+    let exact = Exact { d, limit, vars: RefCell::new(vec![0; d.slots]) };
+    let mut stack = Vec::with_capacity(d.code.len());
+    exact.eval(0, d.code.len(), false, &mut stack)?;
+    let mut sums = BTreeMap::new();
+    for (x, p) in stack.pop().unwrap() {
+        add!(sums, x.sum(), p);
+    }
+    let total: f64 = sums.values().sum();
+    if total == 0.0 {
+        return None;
+    }
+    // Outcomes which a condition rules out entirely are kept with no chance,
+    // as the estimator keeps them with no samples.
+    Some(sums.into_iter().map(|(x, p)| (x, p / total)).collect())
+}
@@ -0,0 +1,199 @@
+use compare;
+use estimator::SudiceResults;
+use png::{self, Canvas, Color};
+
+use std::fmt::Write;
+use std::vec::Vec;
+
+const WIDTH: f64 = 800.0;
+const HEIGHT: f64 = 480.0;
+const MARGIN: f64 = 20.0;
+// Room left of the plot for the probability labels, and below it for the
+// outcome labels.
+const LEFT: f64 = 70.0;
+const BOTTOM: f64 = 40.0;
+const LEGEND_LINE: f64 = 18.0;
+// Outcome labels are thinned out so that no more than this many are drawn.
+const MAX_LABELS: usize = 20;
+
+const BACKGROUND: Color = (0xff, 0xff, 0xff);
+const INK: Color = (0x33, 0x33, 0x33);
+const GRID: Color = (0xdd, 0xdd, 0xdd);
+const PALETTE: &[Color] = &[
+    (0x1f, 0x77, 0xb4), (0xff, 0x7f, 0x0e), (0x2c, 0xa0, 0x2c), (0xd6, 0x27, 0x28),
+    (0x94, 0x67, 0xbd), (0x8c, 0x56, 0x4b), (0xe3, 0x77, 0xc2), (0x7f, 0x7f, 0x7f),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Bar,
+    Line,
+}
+
+// What is plotted against each outcome.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    // The chance of rolling exactly that outcome.
+    Normal,
+    // The chance of rolling that outcome or higher.
+    AtLeast,
+    // The chance of rolling that outcome or lower.
+    AtMost,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Anchor {
+    Start,
+    Middle,
+    End,
+}
+
+// The figure is laid out once as a list of shapes, which are then either
+// written out as SVG or drawn onto a canvas for a PNG.
+enum Shape {
+    Rect { x: f64, y: f64, w: f64, h: f64, color: Color },
+    Line { x1: f64, y1: f64, x2: f64, y2: f64, color: Color },
+    // Positioned by the middle of the left edge, centre or right edge of
+    // the text, according to the anchor.
+    Text { x: f64, y: f64, text: String, anchor: Anchor, color: Color },
+}
+
+// Several distributions charted against a shared axis of outcomes, with a
+// legend keyed by the text of each expression.
+pub struct Figure<'a> {
+    pub series: &'a [(String, SudiceResults)],
+    pub kind: Kind,
+    pub mode: Mode,
+}
+
+impl<'a> Figure<'a> {
+    // Every outcome any series can produce, in increasing order, with what
+    // each series plots against it.
+    pub fn points(&self) -> (Vec<i64>, Vec<Vec<f64>>) {
+        let mut values: Vec<i64> = self.series.iter().flat_map(|(_, r)| r.values.iter().cloned()).collect();
+        values.sort_unstable();
+        values.dedup();
+        let points = self.series.iter().map(|(_, r)| {
+            values.iter().map(|&v| {
+                let (below, above) = r.values.iter().zip(r.dist.iter()).fold((0.0, 0.0), |(below, above), (&x, &p)| {
+                    (below + if x <= v { p } else { 0.0 }, above + if x >= v { p } else { 0.0 })
+                });
+                match self.mode {
+                    Mode::Normal => r.values.binary_search(&v).ok().map_or(0.0, |idx| r.dist[idx]),
+                    Mode::AtLeast => above,
+                    Mode::AtMost => below,
+                }
+            }).collect()
+        }).collect();
+        (values, points)
+    }
+
+    fn layout(&self) -> Vec<Shape> {
+        let mut shapes = Vec::new();
+        let (values, points) = self.points();
+        let ymax = points.iter().flat_map(|p| p.iter().cloned()).fold(0.0, f64::max);
+        let ymax = if ymax > 0.0 { ymax } else { 1.0 };
+
+        // The legend runs across the top, one expression to a line. Any
+        // labels follow the expression, with the chance of each.
+        for (idx, (expr, r)) in self.series.iter().enumerate() {
+            let y = MARGIN + LEGEND_LINE * idx as f64;
+            let color = PALETTE[idx % PALETTE.len()];
+            shapes.push(Shape::Rect { x: LEFT, y, w: 12.0, h: 12.0, color });
+            let mut text = format!("{}: {}", compare::name(idx), expr);
+            if !r.labels.is_empty() {
+                let labels: Vec<String> = r.labels.iter().map(|l| format!("{} {:.1}%", l.label, l.share * 100.0)).collect();
+                write!(text, " ({})", labels.join(", ")).unwrap();
+            }
+            shapes.push(Shape::Text { x: LEFT + 20.0, y: y + 6.0, text, anchor: Anchor::Start, color: INK });
+        }
+
+        let top = MARGIN + LEGEND_LINE * self.series.len() as f64 + MARGIN;
+        let (left, right, bottom) = (LEFT, WIDTH - MARGIN, HEIGHT - BOTTOM);
+        let y = |p: f64| bottom - (bottom - top) * p / ymax;
+
+        // Probabilities are marked at each quarter of the tallest point.
+        for tick in 0..5 {
+            let p = ymax * tick as f64 / 4.0;
+            shapes.push(Shape::Line { x1: left, y1: y(p), x2: right, y2: y(p), color: GRID });
+            let text = format!("{:.1}%", p * 100.0);
+            shapes.push(Shape::Text { x: left - 8.0, y: y(p), text, anchor: Anchor::End, color: INK });
+        }
+
+        let slot = (right - left) / values.len() as f64;
+        let every = values.len().div_ceil(MAX_LABELS).max(1);
+        for (i, v) in values.iter().enumerate().filter(|&(i, _)| i % every == 0) {
+            let x = left + slot * (i as f64 + 0.5);
+            shapes.push(Shape::Line { x1: x, y1: bottom, x2: x, y2: bottom + 4.0, color: INK });
+            shapes.push(Shape::Text { x, y: bottom + 16.0, text: v.to_string(), anchor: Anchor::Middle, color: INK });
+        }
+
+        for (s, points) in points.iter().enumerate() {
+            let color = PALETTE[s % PALETTE.len()];
+            match self.kind {
+                // Each outcome's slot holds a bar from every series side by
+                // side, leaving a fifth of the slot as a gap.
+                Kind::Bar => {
+                    let w = slot * 0.8 / self.series.len() as f64;
+                    for (i, &p) in points.iter().enumerate() {
+                        let x = left + slot * (i as f64 + 0.1) + w * s as f64;
+                        shapes.push(Shape::Rect { x, y: y(p), w, h: bottom - y(p), color });
+                    }
+                },
+                Kind::Line => for i in 1..points.len() {
+                    let (x1, x2) = (left + slot * (i as f64 - 0.5), left + slot * (i as f64 + 0.5));
+                    shapes.push(Shape::Line { x1, y1: y(points[i - 1]), x2, y2: y(points[i]), color });
+                },
+            }
+        }
+
+        shapes.push(Shape::Line { x1: left, y1: top, x2: left, y2: bottom, color: INK });
+        shapes.push(Shape::Line { x1: left, y1: bottom, x2: right, y2: bottom, color: INK });
+        shapes
+    }
+
+    pub fn to_svg(&self) -> String {
+        let mut svg = String::new();
+        let rgb = |(r, g, b): Color| format!("#{:02x}{:02x}{:02x}", r, g, b);
+        writeln!(svg, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" viewBox="0 0 {0} {1}">"#, WIDTH, HEIGHT).unwrap();
+        writeln!(svg, r#"<rect width="100%" height="100%" fill="{}"/>"#, rgb(BACKGROUND)).unwrap();
+        for shape in self.layout() {
+            match shape {
+                Shape::Rect { x, y, w, h, color } => writeln!(svg,
+                    r#"<rect x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" fill="{}"/>"#, x, y, w, h, rgb(color)),
+                Shape::Line { x1, y1, x2, y2, color } => writeln!(svg,
+                    r#"<line x1="{:.1}" y1="{:.1}" x2="{:.1}" y2="{:.1}" stroke="{}" stroke-width="2"/>"#, x1, y1, x2, y2, rgb(color)),
+                Shape::Text { x, y, text, anchor, color } => writeln!(svg,
+                    r#"<text x="{:.1}" y="{:.1}" text-anchor="{}" dominant-baseline="middle" font-family="monospace" font-size="12" fill="{}">{}</text>"#,
+                    x, y, match anchor { Anchor::Start => "start", Anchor::Middle => "middle", Anchor::End => "end" },
+                    rgb(color), escape(&text)),
+            }.unwrap();
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut canvas = Canvas::new(WIDTH as usize, HEIGHT as usize, BACKGROUND);
+        for shape in self.layout() {
+            match shape {
+                Shape::Rect { x, y, w, h, color } => canvas.fill_rect(x, y, w, h, color),
+                Shape::Line { x1, y1, x2, y2, color } => canvas.line(x1, y1, x2, y2, color),
+                Shape::Text { x, y, text, anchor, color } => {
+                    let width = (text.chars().count() * png::CHAR_WIDTH) as f64;
+                    let x = match anchor {
+                        Anchor::Start => x,
+                        Anchor::Middle => x - width / 2.0,
+                        Anchor::End => x - width,
+                    };
+                    canvas.text(x, y - png::CHAR_HEIGHT as f64 / 2.0, &text, color);
+                },
+            }
+        }
+        canvas.encode()
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
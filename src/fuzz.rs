@@ -0,0 +1,208 @@
+use checker;
+use descriptor::{self, SudiceCode};
+use disasm;
+use estimator;
+use interpreter::Interpreter;
+use optimizer;
+use parser::Rdp;
+use typeck;
+
+use pest::prelude::*;
+use rand::{Rng, SeedableRng, StdRng};
+
+use std::panic;
+use std::slice;
+use std::vec::Vec;
+
+// The examples from the README, which between them use every part of the
+// grammar, and a few which stress the edges of the parser and checker.
+pub const CORPUS: &[&str] = &[
+    "3d6\\l1 + 2", "3 + 4", "3 - 4", "3 * 4", "3 / 4", "2d20", "2d3 - 6",
+    "1d2 * 10 + 1d6", "(3 + 4d2) * 10", "(3d6)d2", "3d(1d20)", "3d6d2", "-1d6",
+    "-(1d20)", "|1d6 - 4|", "1d20rr1", "1d20r<3", "1d20r>15", "4d6rl1", "4d6rh2",
+    "1d6rr!1", "1d20r<!3", "1d20r>!15",
+    "3d20\\l1", "3d20\\h1", "(3d20+2)\\l1", "3d20\\l1 + 2", "1d20b2", "(3d3d3 * 4 + 1d2 * 4)w3",
+    "[1d2 ? 1d20w2 : 1d20b2+10]", "[1d3 ? 1d2 1d4 : 1d8]", "1d20 > 8", "1d100 < 24",
+    "1d20 == 1", "1d20 != 1", "1d20 != 1 and 1d20 != 20", "1d20 == 1 or 1d20 == 20",
+    "1d20 > 10 xor 1d20 > 10", "not 1d20 == 1 or 1d20 == 20", "[1d2 ? true : 1d6 > 4]",
+    "#(1d20 > 8) + #(1d20 > 8) + #(1d20 > 8)",
+    "1d9223372036854775807 < 2", "-9223372036854775808 / -1", "0d6", "1d6/(1d2-1)",
+    "3 + 4d * 1", "1d100 % 10", "2 ** 1d6", "1d6 ** 2 ** 2", "min(1d20, 1d20)", "max(2d6, 4) % 3",
+    "8d6 / 2", "8d6 /^ 2", "8d6 /~ 2",
+    "[1d20 ? \"crit\": 2d8+4 : \"hit\": 1d8+4]", "[1d20 > 10 ? \"hit\": 1d8 : \"miss\": 0]b2",
+    "let A = 1d20 in (A, A > 15)", "let R = 1d20 in (#(R == 20), [R == 20 ? 2d6 : 1d6])", "(1d6, 2d6, 3d6)",
+    "let A = 2d6 in let A = A * A in A - 1", "let N = 1d4 in Nd6\\lN",
+    "let R = 1d20 in R given R > 10", "let R = 1d20 in (R, 2d6 + R) given R == 20", "1d20 > 10 given true",
+    "(1d6)s3", "[1d20 > 10 ? 1d8 : 0]s3", "(2d6)c4\\l1", "(1d20b2)c3\\h1^15", "(1d6 - 3)c(1d4 + 1)_0",
+    "largest(4d10)", "highest(4d10, 2)", "pairs(5d6)", "longest(5d6)", "unique((1d6 - 3)c4)",
+    "[1d2 ? 1d6 largest(3d6) : highest(9223372036854775807d1, 1d3)]",
+];
+
+// Fragments spliced into inputs, so that mutations mostly produce
+// something close enough to the grammar to get past the parser.
+const TOKENS: &[&str] = &[
+    "d", "rr", "rl", "rh", "r<", "r>", "rr!", "r<!", "r>!", "\\l", "\\h", "^", "_", "b", "w", "s", "c", "+", "-", "*", "/", "/^", "/~", "%", "**",
+    "(", ")", "[", "]", "?", ":", "|", "<", ">", "==", "!=", " and ", " or ",
+    " xor ", "not ", "#", "true", "false", "min(", "max(", ",", "let A = ", " in ", "A", " given ",
+    "largest(", "highest(", "pairs(", "longest(", "unique(",
+    "\"", "\"a\": ",
+    "0", "1", "2", "6", "20", "-1", "9223372036854775807", "-9223372036854775808",
+    "99999999999999999999", " ", "é", "→",
+];
+
+// Inputs are kept short, as the parser recurses once per level of nesting.
+const MAX_LEN: usize = 64;
+
+// Any literal bigger than this could make a single sample take forever,
+// such as a billion dice, so such expressions are checked but not sampled.
+// Powers can reach as far from small literals, so they are only sampled
+// when their exponent is a constant which keeps them within the limit.
+const SAMPLE_LIMIT: i64 = 100;
+
+const SAMPLES: usize = 16;
+
+// Expressions with no more outcomes than this are also estimated, which
+// takes a couple of thousand samples per outcome.
+const ESTIMATE_LIMIT: u64 = 4;
+
+fn mutate<R: Rng>(rng: &mut R, input: &str) -> String {
+    let mut chars: Vec<char> = input.chars().collect();
+    for _ in 0..rng.gen_range(1, 4) {
+        let at = rng.gen_range(0, chars.len() + 1);
+        match rng.gen_range(0, 5) {
+            0 => {
+                let token = rng.choose(TOKENS).unwrap();
+                for (i, c) in token.chars().enumerate() {
+                    chars.insert(at + i, c);
+                }
+            },
+            1 if at < chars.len() => {
+                let end = rng.gen_range(at, chars.len()) + 1;
+                chars.drain(at..end);
+            },
+            2 if at < chars.len() => chars[at] = rng.gen_range(0x20u8, 0x7f) as char,
+            3 => {
+                let other: Vec<char> = rng.choose(CORPUS).unwrap().chars().collect();
+                let start = rng.gen_range(0, other.len());
+                let end = rng.gen_range(start, other.len()) + 1;
+                for (i, &c) in other[start..end].iter().enumerate() {
+                    chars.insert(at + i, c);
+                }
+            },
+            _ => {
+                let token = rng.choose(TOKENS).unwrap();
+                chars.truncate(at);
+                chars.extend(token.chars());
+            },
+        }
+    }
+    chars.truncate(MAX_LEN);
+    chars.into_iter().collect()
+}
+
+// Whether an expression is cheap enough to sample, judging each power by
+// the constant it folds to when both its operands are constants, and
+// otherwise by its exponent, which may raise no further than a product of
+// two operands could.
+fn small(code: &[SudiceCode]) -> bool {
+    let mut stack: Vec<Option<i64>> = Vec::with_capacity(code.len());
+    for c in code {
+        let value = match *c {
+            SudiceCode::Num(i) => Some(i),
+            SudiceCode::Pow => match (stack.pop().unwrap(), stack.pop().unwrap()) {
+                (Some(base), Some(exponent)) => match descriptor::checked_pow(base, exponent) {
+                    Some(i) => Some(i),
+                    None => return false,
+                },
+                (_, Some(exponent)) if exponent <= 2 => None,
+                _ => return false,
+            },
+            // Only the last branch of a select leaves its value behind.
+            SudiceCode::Jump(offset) => {
+                stack.pop();
+                if offset > 0 {
+                    continue;
+                }
+                None
+            },
+            SudiceCode::Select(_) | SudiceCode::Bind(..) => {
+                stack.pop();
+                continue;
+            },
+            ref c => {
+                for _ in c.stack_effect()..1 {
+                    stack.pop();
+                }
+                None
+            },
+        };
+        if value.is_some_and(|i| !(-SAMPLE_LIMIT..=SAMPLE_LIMIT).contains(&i)) {
+            return false;
+        }
+        stack.push(value);
+    }
+    true
+}
+
+// Feeds an input through everything the REPL would do with it, and then
+// some. Every failure along the way should be an error, never a panic.
+pub fn run_one<R: Rng>(rng: &mut R, input: &str) {
+    let mut parser = Rdp::new(StringInput::new(input));
+    if !parser.expr() || !parser.end() {
+        let _ = parser.expected();
+        return;
+    }
+    let code = match parser.compile() {
+        Ok(code) => code,
+        Err(e) => {
+            let _ = e.span.excerpt(input);
+            return;
+        },
+    };
+    let _ = disasm::disassemble(&code).to_string();
+    if let Err(e) = typeck::type_check(&code) {
+        let _ = e.span.excerpt(&code.source);
+        return;
+    }
+    let supports = match checker::joint_check(&code) {
+        Ok(supports) => supports,
+        Err(e) => {
+            let _ = e.span.excerpt(&code.source);
+            return;
+        },
+    };
+    let optimized = optimizer::optimize(&code);
+    let _ = disasm::disassemble(&optimized).to_string();
+    if checker::joint_range_check(&code, &supports, 1 << 16).is_err() {
+        return;
+    }
+    if !small(&code.code) {
+        return;
+    }
+    let mut interpreter = Interpreter::new(&optimized);
+    for _ in 0..SAMPLES {
+        let x = interpreter.sample(rng);
+        let outcome = if supports.len() > 1 { interpreter.components() } else { slice::from_ref(&x) };
+        for (&x, support) in outcome.iter().zip(&supports) {
+            assert!(support.contains(x), "Sampled {}, which the checker found unreachable.", x);
+        }
+    }
+    if supports.len() == 1 && supports[0].len() <= ESTIMATE_LIMIT {
+        let _ = estimator::estimate_with(&optimized, &supports[0], rng).to_string();
+    }
+}
+
+// Runs `iterations` inputs mutated from the corpus, returning the first
+// which panicked. The same seed always tries the same inputs.
+pub fn fuzz(seed: usize, iterations: usize) -> Option<String> {
+    let mut rng = StdRng::from_seed(&[seed][..]);
+    for _ in 0..iterations {
+        let seed = rng.choose(CORPUS).unwrap();
+        let input = mutate(&mut rng, seed);
+        let inner = rng.gen::<usize>();
+        if panic::catch_unwind(|| run_one(&mut StdRng::from_seed(&[inner][..]), &input)).is_err() {
+            return Some(input);
+        }
+    }
+    None
+}
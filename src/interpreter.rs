@@ -1,175 +1,174 @@
-use descriptor::{SudiceCode, SudiceExpression};
+use descriptor::{self, from_bool, SudiceCode, SudiceExpression, REROLL_DEPTH};
 
 use rand::distributions::{IndependentSample, Range};
-use rand::ThreadRng;
+use rand::Rng;
 
 use std::cmp;
+use std::mem;
 use std::vec::Vec;
 
-#[derive(Debug)]
-enum SudiceValue {
-    Scalar(i64),
-    Vector(i64, Vec<i64>)
-}
+// A die with faces 1 through its size. The range is drawn from starting at
+// zero so that a die as large as i64::MAX does not overflow its bound.
+struct Die(Range<i64>);
 
-trait HasSudiceValue {
-    fn as_value(self) -> SudiceValue;
-}
+impl Die {
+    fn new(size: i64) -> Die {
+        Die(Range::new(0, size))
+    }
 
-impl HasSudiceValue for i64 {
-    fn as_value(self) -> SudiceValue {
-        SudiceValue::Scalar(self)
+    fn roll<R: Rng>(&self, r: &mut R) -> i64 {
+        self.0.ind_sample(r) + 1
     }
 }
 
-impl HasSudiceValue for SudiceValue {
-    fn as_value(self) -> SudiceValue {
-        self
-    }
+#[derive(Debug)]
+enum SudiceValue {
+    Scalar(i64),
+    Vector(i64, Vec<i64>)
 }
 
+// Dice in a vector are kept in no particular order. Operations which care
+// about the lowest or highest few partition around them instead of sorting.
 impl SudiceValue {
-    fn new<T: HasSudiceValue>(value: T) -> SudiceValue {
-        value.as_value()
-    }
-
-    fn collapse(self) -> i64 {
+    // Rerolls every die showing a face which `pick` chooses, and then
+    // rerolls it again while it still does, up to `depth` times in all.
+    fn reroll_where<R: Rng, F: Fn(i64) -> bool>(self, pick: F, depth: usize, r: &mut R) -> Result<SudiceValue, String> {
         match self {
-            SudiceValue::Scalar(i) => i,
-            SudiceValue::Vector(_, s) => s.iter().fold(0, |acc, &x| acc + x),
+            SudiceValue::Scalar(_) => Err("Cannot reroll a scalar value.".to_string()),
+            SudiceValue::Vector(x, mut v) => {
+                let btwn = Die::new(x);
+                for face in v.iter_mut() {
+                    for _ in 0..depth {
+                        if !pick(*face) {
+                            break;
+                        }
+                        *face = btwn.roll(r);
+                    }
+                }
+                Ok(SudiceValue::Vector(x, v))
+            },
         }
     }
 
-    fn add<T: HasSudiceValue>(self, value: T) -> SudiceValue {
-        SudiceValue::Scalar(self.collapse() + value.as_value().collapse())
+    fn reroll<R: Rng>(self, n: i64, r: &mut R) -> Result<SudiceValue, String> {
+        self.reroll_where(|face| face == n, 1, r)
     }
 
-    fn sub<T: HasSudiceValue>(self, value: T) -> SudiceValue {
-        SudiceValue::Scalar(self.collapse() - value.as_value().collapse())
+    fn reroll_below<R: Rng>(self, n: i64, r: &mut R) -> Result<SudiceValue, String> {
+        self.reroll_where(|face| face < n, 1, r)
     }
 
-    fn mul<T: HasSudiceValue>(self, value: T) -> SudiceValue {
-        SudiceValue::Scalar(self.collapse() * value.as_value().collapse())
+    fn reroll_above<R: Rng>(self, n: i64, r: &mut R) -> Result<SudiceValue, String> {
+        self.reroll_where(|face| face > n, 1, r)
     }
 
-    fn div<T: HasSudiceValue>(self, value: T) -> SudiceValue {
-        SudiceValue::Scalar(self.collapse() / value.as_value().collapse())
+    fn reroll_until<R: Rng>(self, n: i64, r: &mut R) -> Result<SudiceValue, String> {
+        self.reroll_where(|face| face == n, REROLL_DEPTH, r)
     }
 
-    fn roll<T: HasSudiceValue, S: HasSudiceValue>(num: T, size: S, r: &mut ThreadRng) -> SudiceValue {
-        let n = num.as_value().collapse();
-        let x = size.as_value().collapse();
-        let btwn = Range::new(1, x+1);
-        assert!(n > 0);
-        let mut v = Vec::with_capacity(n as usize);
-        for _ in 0..n {
-            v.push(btwn.ind_sample(r));
-        }
-        v.sort();
-        SudiceValue::Vector(x, v)
+    fn reroll_below_until<R: Rng>(self, n: i64, r: &mut R) -> Result<SudiceValue, String> {
+        self.reroll_where(|face| face < n, REROLL_DEPTH, r)
     }
 
-    fn reroll<T: HasSudiceValue>(self, value: T, r: &mut ThreadRng) -> SudiceValue {
-        let n = value.as_value().collapse();
-        match self {
-            SudiceValue::Scalar(_) => panic!("Error: Cannot reroll a scalar value."),
-            SudiceValue::Vector(x, mut v) => {
-                let btwn = Range::new(1, x+1);
-                for i in 0..v.len() {
-                    if v[i] == n {
-                        v[i] = btwn.ind_sample(r);
-                    }
-                }
-                SudiceValue::Vector(x, v)
-            },
-        }
+    fn reroll_above_until<R: Rng>(self, n: i64, r: &mut R) -> Result<SudiceValue, String> {
+        self.reroll_where(|face| face > n, REROLL_DEPTH, r)
     }
 
-    fn reroll_lowest<T: HasSudiceValue>(self, value: T, r: &mut ThreadRng) -> SudiceValue {
-        let v = value.as_value().collapse();
-        assert!(v >= 0);
-        let n = v as usize;
+    fn reroll_lowest<R: Rng>(self, n: i64, r: &mut R) -> Result<SudiceValue, String> {
+        if n < 0 {
+            return Err("Cannot reroll a negative number of dice.".to_string());
+        }
+        let n = n as usize;
         match self {
-            SudiceValue::Scalar(_) => panic!("Error: Cannot reroll a scalar value."),
+            SudiceValue::Scalar(_) => Err("Cannot reroll a scalar value.".to_string()),
             SudiceValue::Vector(x, mut v) => {
                 if n > v.len() {
-                    panic!("Error: Cannot reroll {} from {} rolls.", n, v.len());
+                    return Err(format!("Cannot reroll {} from {} rolls.", n, v.len()));
                 }
-                let btwn = Range::new(1, x+1);
-                for i in 0..n {
-                    v[i] = btwn.ind_sample(r);
+                if n > 0 {
+                    v.select_nth_unstable(n-1);
+                    let btwn = Die::new(x);
+                    for face in v.iter_mut().take(n) {
+                        *face = btwn.roll(r);
+                    }
                 }
-                v.sort();
-                SudiceValue::Vector(x, v)
+                Ok(SudiceValue::Vector(x, v))
             },
         }
     }
 
-    fn reroll_highest<T: HasSudiceValue>(self, value: T, r: &mut ThreadRng) -> SudiceValue {
-        let v = value.as_value().collapse();
-        assert!(v >= 0);
-        let n = v as usize;
+    fn reroll_highest<R: Rng>(self, n: i64, r: &mut R) -> Result<SudiceValue, String> {
+        if n < 0 {
+            return Err("Cannot reroll a negative number of dice.".to_string());
+        }
+        let n = n as usize;
         match self {
-            SudiceValue::Scalar(_) => panic!("Error: Cannot reroll a scalar value."),
+            SudiceValue::Scalar(_) => Err("Cannot reroll a scalar value.".to_string()),
             SudiceValue::Vector(x, mut v) => {
                 if n > v.len() {
-                    panic!("Error: Cannot reroll {} from {} rolls.", n, v.len());
+                    return Err(format!("Cannot reroll {} from {} rolls.", n, v.len()));
                 }
-                let btwn = Range::new(1, x+1);
-                for i in (v.len()-n)..v.len() {
-                    v[i] = btwn.ind_sample(r);
+                if n > 0 {
+                    let len = v.len();
+                    v.select_nth_unstable(len-n);
+                    let btwn = Die::new(x);
+                    for face in v.iter_mut().skip(len-n) {
+                        *face = btwn.roll(r);
+                    }
                 }
-                v.sort();
-                SudiceValue::Vector(x, v)
+                Ok(SudiceValue::Vector(x, v))
             },
         }
     }
 
-    fn drop_lowest<T: HasSudiceValue>(self, value: T) -> SudiceValue {
-        let v = value.as_value().collapse();
-        assert!(v >= 0);
-        let n = v as usize;
+    fn drop_lowest(self, n: i64) -> Result<SudiceValue, String> {
+        if n < 0 {
+            return Err("Cannot drop a negative number of dice.".to_string());
+        }
+        let n = n as usize;
         match self {
-            SudiceValue::Scalar(_) => panic!("Error: Cannot drop a scalar value."),
+            SudiceValue::Scalar(_) => Err("Cannot drop a scalar value.".to_string()),
             SudiceValue::Vector(x, mut v) => {
-                let len = v.len();
-                if n >= len {
-                    panic!("Error: Cannot drop {} from {} rolls.", n, v.len());
+                if n >= v.len() {
+                    return Err(format!("Cannot drop {} from {} rolls.", n, v.len()));
                 }
-                for i in 0..(len-n) {
-                    v[i] = v[i+n];
+                if n > 0 {
+                    v.select_nth_unstable(n);
+                    v.drain(..n);
                 }
-                v.truncate(len-n);
-                SudiceValue::Vector(x, v)
+                Ok(SudiceValue::Vector(x, v))
             },
         }
     }
 
-    fn drop_highest<T: HasSudiceValue>(self, value: T) -> SudiceValue {
-        let v = value.as_value().collapse();
-        assert!(v >= 0);
-        let n = v as usize;
+    fn drop_highest(self, n: i64) -> Result<SudiceValue, String> {
+        if n < 0 {
+            return Err("Cannot drop a negative number of dice.".to_string());
+        }
+        let n = n as usize;
         match self {
-            SudiceValue::Scalar(_) => panic!("Error: Cannot drop a scalar value."),
+            SudiceValue::Scalar(_) => Err("Cannot drop a scalar value.".to_string()),
             SudiceValue::Vector(x, mut v) => {
                 let len = v.len();
                 if n >= len {
-                    panic!("Error: Cannot drop {} from {} rolls.", n, v.len());
+                    return Err(format!("Cannot drop {} from {} rolls.", n, v.len()));
                 }
-                v.truncate(len-n);
-                SudiceValue::Vector(x, v)
+                if n > 0 {
+                    v.select_nth_unstable(len-n);
+                    v.truncate(len-n);
+                }
+                Ok(SudiceValue::Vector(x, v))
             },
         }
     }
 
-    fn ceil<T: HasSudiceValue>(self, value: T) -> SudiceValue {
-        let n = value.as_value().collapse();
+    fn ceil(self, n: i64) -> SudiceValue {
         match self {
-            SudiceValue::Scalar(s) => if s > n { SudiceValue::Scalar(n) } else { SudiceValue::Scalar(s) },
+            SudiceValue::Scalar(s) => SudiceValue::Scalar(cmp::min(s, n)),
             SudiceValue::Vector(x, mut v) => {
-                for i in 0..v.len() {
-                    if v[i] > n {
-                        v[i] = n;
+                for face in v.iter_mut() {
+                    if *face > n {
+                        *face = n;
                     }
                 }
                 SudiceValue::Vector(x, v)
@@ -177,165 +176,467 @@ impl SudiceValue {
         }
     }
 
-    fn floor<T: HasSudiceValue>(self, value: T) -> SudiceValue {
-        let n = value.as_value().collapse();
+    fn floor(self, n: i64) -> SudiceValue {
         match self {
-            SudiceValue::Scalar(s) => if s < n { SudiceValue::Scalar(n) } else { SudiceValue::Scalar(s) },
+            SudiceValue::Scalar(s) => SudiceValue::Scalar(cmp::max(s, n)),
             SudiceValue::Vector(x, mut v) => {
-                for i in 0..v.len() {
-                    if v[i] < n {
-                        v[i] = n;
+                for face in v.iter_mut() {
+                    if *face < n {
+                        *face = n;
                     }
                 }
                 SudiceValue::Vector(x, v)
             },
         }
     }
+}
 
-    fn into_bool(self) -> bool {
-        self.collapse() == 1
-    }
-
-    fn from_bool(x: bool) -> SudiceValue {
-        if x { SudiceValue::Scalar(1) } else { SudiceValue::Scalar(2) }
-    }
-
-    fn lt<T: HasSudiceValue>(self, value: T) -> SudiceValue {
-        SudiceValue::from_bool(self.collapse() < value.as_value().collapse())
-    }
-
-    fn gt<T: HasSudiceValue>(self, value: T) -> SudiceValue {
-        SudiceValue::from_bool(self.collapse() > value.as_value().collapse())
-    }
-
-    fn eq<T: HasSudiceValue>(self, value: T) -> SudiceValue {
-        SudiceValue::from_bool(self.collapse() == value.as_value().collapse())
-    }
-
-    fn ne<T: HasSudiceValue>(self, value: T) -> SudiceValue {
-        SudiceValue::from_bool(self.collapse() != value.as_value().collapse())
-    }
-
-    fn and<T: HasSudiceValue>(self, value: T) -> SudiceValue {
-        SudiceValue::from_bool(self.into_bool() && value.as_value().into_bool())
-    }
-
-    fn or<T: HasSudiceValue>(self, value: T) -> SudiceValue {
-        SudiceValue::from_bool(self.into_bool() || value.as_value().into_bool())
-    }
+// The checker rules out every failure while sampling, so reaching one is a
+// bug, reported against the part of the expression which caused it.
+fn fail(d: &SudiceExpression, dcp: usize, message: &str) -> ! {
+    panic!("Error in `{}`: {}", d.spans[dcp].excerpt(&d.source), message)
+}
 
-    fn abs(self) -> SudiceValue {
-        SudiceValue::Scalar(self.collapse().abs())
+// Sums a value, handing any vector it held back to be reused by a later
+// roll. Arithmetic saturates instead of wrapping. The checker rejects
+// anything whose bounds overflow, so this is only a backstop.
+fn collapse(value: SudiceValue, spare: &mut Vec<Vec<i64>>) -> i64 {
+    match value {
+        SudiceValue::Scalar(i) => i,
+        SudiceValue::Vector(_, mut v) => {
+            let sum = v.iter().fold(0, |acc: i64, &x| acc.saturating_add(x));
+            v.clear();
+            spare.push(v);
+            sum
+        },
     }
+}
 
-    fn neg(self) -> SudiceValue {
-        SudiceValue::Scalar(-self.collapse())
+// Sorts the dice of a pool so that `f` can count off its sets of equal
+// faces, handing the vector back to be reused by a later roll.
+fn matched<F: Fn(&[i64]) -> i64>(value: SudiceValue, spare: &mut Vec<Vec<i64>>, f: F) -> Result<i64, String> {
+    match value {
+        SudiceValue::Scalar(_) => Err("Cannot match faces of a scalar value.".to_string()),
+        SudiceValue::Vector(_, mut v) => {
+            v.sort_unstable();
+            let x = f(&v);
+            v.clear();
+            spare.push(v);
+            Ok(x)
+        },
     }
 }
 
+// `label` is the last label taken by the run whose value was kept, if it
+// took any, so that picking the best of several runs picks its label along
+// with it. Runs which are all kept leave the label of the last of them.
+// `pool` holds every run so far when they are being collected rather than
+// folded into `value`.
 struct Accumulator {
     pub ptr: usize,
     pub count: i64,
-    pub value: i64
+    pub value: i64,
+    pub label: Option<usize>,
+    pub pool: Option<Vec<i64>>,
 }
 
 impl Accumulator {
-    pub fn new(ptr: usize, count: i64, init: i64) -> Accumulator {
+    pub fn new(ptr: usize, count: i64, init: i64, label: Option<usize>, mut pool: Option<Vec<i64>>) -> Accumulator {
+        if let Some(ref mut v) = pool {
+            v.push(init);
+        }
         Accumulator {
-            ptr: ptr,
-            count: count,
-            value: init
+            ptr,
+            count,
+            value: init,
+            label,
+            pool,
         }
     }
+
+    fn finish(self) -> SudiceValue {
+        match self.pool {
+            Some(v) => SudiceValue::Vector(0, v),
+            None => SudiceValue::Scalar(self.value),
+        }
+    }
+}
+
+// Samples a compiled expression over and over, reusing its stacks and the
+// vectors that hold dice between samples.
+pub struct Interpreter<'a> {
+    code: &'a SudiceExpression,
+    summed: Vec<bool>,
+    stack: Vec<SudiceValue>,
+    accums: Vec<Accumulator>,
+    spare: Vec<Vec<i64>>,
+    // The position of the label ending the last labelled branch taken in
+    // the latest sample, if any was.
+    label: Option<usize>,
+    // The positions of the labels taken so far in a sample, in the order
+    // they were taken, less those taken by runs of a repeat which were not
+    // kept. The last of them labels the sample.
+    labels: Vec<usize>,
+    // The value bound to each slot by the lets being evaluated.
+    vars: Vec<i64>,
+    // The components of the tuple the latest sample ended in, if any.
+    tuple: Vec<i64>,
+    // Whether the latest sample failed the condition it was given.
+    rejected: bool,
 }
 
-pub fn interpret(d: &SudiceExpression, r: &mut ThreadRng) -> i64 {
-    let mut l: Vec<Accumulator> = Vec::with_capacity(d.code.len());
-    let mut s = Vec::with_capacity(d.code.len());
-    let mut tos = SudiceValue::Scalar(0);
-    let mut dcp = 0;
-    macro_rules! op2 {
-        ($func:path) => {{
-            let x = s.pop().unwrap();
-            tos = $func(tos, x);
-        }}
+impl<'a> Interpreter<'a> {
+    pub fn new(d: &'a SudiceExpression) -> Interpreter<'a> {
+        Interpreter {
+            code: d,
+            summed: summed_rolls(d),
+            stack: Vec::with_capacity(d.code.len()),
+            accums: Vec::with_capacity(d.code.len()),
+            spare: Vec::new(),
+            label: None,
+            labels: Vec::new(),
+            vars: vec![0; d.slots],
+            tuple: Vec::new(),
+            rejected: false,
+        }
+    }
+
+    pub fn label(&self) -> Option<usize> {
+        self.label
     }
 
-    macro_rules! rop {
-        ($func:path) => {{
-            let x = s.pop().unwrap();
-            tos = $func(tos, x, r);
-        }}
+    pub fn components(&self) -> &[i64] {
+        &self.tuple
     }
 
-    macro_rules! accum {
-        ($func:path, $offset:ident) => {{
-            let len = l.len();
-            if len > 0 && l[len-1].ptr == dcp {
-                if l[len-1].count <= 0 {
-                    let a = l.pop().unwrap();
-                    tos = SudiceValue::Scalar(a.value);
+    pub fn rejected(&self) -> bool {
+        self.rejected
+    }
+
+    pub fn sample<R: Rng>(&mut self, r: &mut R) -> i64 {
+        let d = self.code;
+        let summed = &self.summed;
+        let s = &mut self.stack;
+        let l = &mut self.accums;
+        let spare = &mut self.spare;
+        let vars = &mut self.vars;
+        let tuple = &mut self.tuple;
+        let labels = &mut self.labels;
+        labels.clear();
+        let mut tos = SudiceValue::Scalar(0);
+        let mut rejected = false;
+        let mut dcp = 0;
+        macro_rules! op2 {
+            ($func:expr) => {{
+                let y = collapse(s.pop().unwrap(), spare);
+                let x = collapse(mem::replace(&mut tos, SudiceValue::Scalar(0)), spare);
+                tos = SudiceValue::Scalar($func(x, y));
+            }}
+        }
+        macro_rules! op1 {
+            ($func:expr) => {{
+                let x = collapse(mem::replace(&mut tos, SudiceValue::Scalar(0)), spare);
+                tos = SudiceValue::Scalar($func(x));
+            }}
+        }
+        macro_rules! pool_op {
+            ($func:expr) => {{
+                let x = matched(mem::replace(&mut tos, SudiceValue::Scalar(0)), spare, $func);
+                tos = SudiceValue::Scalar(x.unwrap_or_else(|e| fail(d, dcp, &e)));
+            }}
+        }
+        macro_rules! dice_op {
+            ($func:path) => {{
+                let n = collapse(s.pop().unwrap(), spare);
+                tos = $func(tos, n);
+            }};
+            ($func:path, checked) => {{
+                let n = collapse(s.pop().unwrap(), spare);
+                tos = $func(tos, n).unwrap_or_else(|e| fail(d, dcp, &e));
+            }};
+            ($func:path, rng) => {{
+                let n = collapse(s.pop().unwrap(), spare);
+                tos = $func(tos, n, r).unwrap_or_else(|e| fail(d, dcp, &e));
+            }}
+        }
+        // A roll which will only ever be summed never needs its dice kept
+        // around, so it is summed as it is sampled.
+        macro_rules! roll {
+            ($n:expr, $x:expr) => {{
+                let (n, x) = ($n, $x);
+                if n < 0 {
+                    fail(d, dcp, "Cannot roll a negative number of dice.");
+                }
+                let btwn = Die::new(x);
+                if summed[dcp] {
+                    let mut sum = 0i64;
+                    for _ in 0..n {
+                        sum = sum.saturating_add(btwn.roll(r));
+                    }
+                    SudiceValue::Scalar(sum)
                 } else {
-                    l[len-1].value = $func(l[len-1].value, tos.collapse());
-                    tos = s.pop().unwrap();
-                    l[len-1].count -= 1;
-                    dcp -= $offset + 1;
+                    let mut v = spare.pop().unwrap_or_default();
+                    for _ in 0..n {
+                        v.push(btwn.roll(r));
+                    }
+                    SudiceValue::Vector(x, v)
                 }
-            } else {
-                let x = s.pop().unwrap().collapse();
-                if x > 1 {
-                    l.push(Accumulator::new(dcp, x - 1, tos.collapse()));
-                    tos = s.pop().unwrap();
-                    dcp -= $offset + 1;
+            }}
+        }
+        // Takes back the labels taken by the run of the `$offset`
+        // instructions before this one which just ended, leaving those taken
+        // before it began, and gives the last of them.
+        macro_rules! run_label {
+            ($offset:ident) => {{
+                let mut base = labels.len();
+                while base > 0 && labels[base-1] >= dcp - $offset {
+                    base -= 1;
+                }
+                let last = labels[base..].last().cloned();
+                labels.truncate(base);
+                last
+            }}
+        }
+        // Runs the `$offset` instructions before this one again until they
+        // have been run as many times as the operand beneath them says,
+        // folding each run after the first into the accumulator with `$step`.
+        // Only the labels of the runs kept are taken back up once done. When
+        // `$pass`, a single run is passed on as it is, dice and all.
+        macro_rules! accum {
+            ($offset:ident, $pool:expr, $pass:expr, |$acc:ident, $x:ident, $label:ident| $step:block) => {{
+                let len = l.len();
+                if len > 0 && l[len-1].ptr == dcp {
+                    let $x = collapse(mem::replace(&mut tos, SudiceValue::Scalar(0)), spare);
+                    let $label = run_label!($offset);
+                    {
+                        let $acc = &mut l[len-1];
+                        $step
+                    }
+                    l[len-1].count -= 1;
+                    if l[len-1].count <= 0 {
+                        let acc = l.pop().unwrap();
+                        labels.extend(acc.label);
+                        tos = acc.finish();
+                    } else {
+                        tos = s.pop().unwrap();
+                        dcp -= $offset + 1;
+                    }
+                } else {
+                    let n = collapse(s.pop().unwrap(), spare);
+                    if n <= 1 && $pass {
+                        let label = run_label!($offset);
+                        labels.extend(label);
+                    } else {
+                        let init = collapse(mem::replace(&mut tos, SudiceValue::Scalar(0)), spare);
+                        let pool = if $pool { Some(spare.pop().unwrap_or_default()) } else { None };
+                        let acc = Accumulator::new(dcp, n - 1, init, run_label!($offset), pool);
+                        if n > 1 {
+                            tos = s.pop().unwrap();
+                            l.push(acc);
+                            dcp -= $offset + 1;
+                        } else {
+                            labels.extend(acc.label);
+                            tos = acc.finish();
+                        }
+                    }
                 }
+            }}
+        }
+        macro_rules! pick {
+            ($func:path, $offset:ident) => {
+                accum!($offset, false, true, |acc, x, label| {
+                    let kept = $func(acc.value, x);
+                    if kept != acc.value {
+                        acc.label = label;
+                    }
+                    acc.value = kept;
+                })
+            }
+        }
+        while dcp < d.code.len() {
+            match d.code[dcp] {
+                SudiceCode::Num(i) => {
+                    s.push(mem::replace(&mut tos, SudiceValue::Scalar(i)));
+                },
+                SudiceCode::Bool(b) => {
+                    s.push(mem::replace(&mut tos, SudiceValue::Scalar(from_bool(b))));
+                },
+                SudiceCode::RollConst(n, x) => {
+                    let v = roll!(n, x);
+                    s.push(mem::replace(&mut tos, v));
+                },
+                SudiceCode::Add => op2!(i64::saturating_add),
+                SudiceCode::Sub => op2!(i64::saturating_sub),
+                SudiceCode::Mul => op2!(i64::saturating_mul),
+                // Only i64::MIN / -1 overflows, whichever way it is rounded.
+                SudiceCode::Div => op2!(|x, y| descriptor::checked_div_floor(x, y).unwrap_or(i64::MAX)),
+                SudiceCode::DivCeil => op2!(|x, y| descriptor::checked_div_ceil(x, y).unwrap_or(i64::MAX)),
+                SudiceCode::DivRound => op2!(|x, y| descriptor::checked_div_round(x, y).unwrap_or(i64::MAX)),
+                SudiceCode::Mod => op2!(|x, y| descriptor::checked_rem_floor(x, y).unwrap_or(0)),
+                SudiceCode::Pow => op2!(descriptor::saturating_pow),
+                SudiceCode::Min => op2!(cmp::min),
+                SudiceCode::Max => op2!(cmp::max),
+                SudiceCode::Roll => {
+                    let x = collapse(s.pop().unwrap(), spare);
+                    let n = collapse(mem::replace(&mut tos, SudiceValue::Scalar(0)), spare);
+                    tos = roll!(n, x);
+                },
+                SudiceCode::Reroll => dice_op!(SudiceValue::reroll, rng),
+                SudiceCode::RerollLowest => dice_op!(SudiceValue::reroll_lowest, rng),
+                SudiceCode::RerollHighest => dice_op!(SudiceValue::reroll_highest, rng),
+                SudiceCode::RerollBelow => dice_op!(SudiceValue::reroll_below, rng),
+                SudiceCode::RerollAbove => dice_op!(SudiceValue::reroll_above, rng),
+                SudiceCode::RerollUntil => dice_op!(SudiceValue::reroll_until, rng),
+                SudiceCode::RerollBelowUntil => dice_op!(SudiceValue::reroll_below_until, rng),
+                SudiceCode::RerollAboveUntil => dice_op!(SudiceValue::reroll_above_until, rng),
+                SudiceCode::DropLowest => dice_op!(SudiceValue::drop_lowest, checked),
+                SudiceCode::DropHighest => dice_op!(SudiceValue::drop_highest, checked),
+                SudiceCode::Ceil => dice_op!(SudiceValue::ceil),
+                SudiceCode::Floor => dice_op!(SudiceValue::floor),
+                SudiceCode::BestOf(offset) => pick!(cmp::max, offset),
+                SudiceCode::WorstOf(offset) => pick!(cmp::min, offset),
+                SudiceCode::SumOf(offset) => accum!(offset, false, false, |acc, x, label| {
+                    acc.value = acc.value.saturating_add(x);
+                    acc.label = label;
+                }),
+                SudiceCode::CollectOf(offset) => accum!(offset, true, false, |acc, x, label| {
+                    acc.pool.as_mut().unwrap().push(x);
+                    acc.label = label;
+                }),
+                SudiceCode::Largest => pool_op!(descriptor::largest),
+                SudiceCode::Pairs => pool_op!(descriptor::pairs),
+                SudiceCode::Longest => pool_op!(descriptor::longest),
+                SudiceCode::Unique => pool_op!(descriptor::unique),
+                SudiceCode::Highest => {
+                    let k = collapse(s.pop().unwrap(), spare);
+                    pool_op!(|v| descriptor::highest(v, k));
+                },
+                SudiceCode::Select(ref offsets) => {
+                    let t = collapse(mem::replace(&mut tos, s.pop().unwrap()), spare);
+                    let x = t - 2;
+                    let len = offsets.len();
+                    if x >= 0 && x < (len-2) as i64 {
+                        dcp += offsets[x as usize];
+                    } else if t != 1 {
+                        dcp += offsets[len-2];
+                    }
+                },
+                SudiceCode::Jump(offset) => dcp += offset,
+                SudiceCode::Label(_) => labels.push(dcp),
+                SudiceCode::Bind(slot, _) => {
+                    vars[slot] = collapse(mem::replace(&mut tos, s.pop().unwrap()), spare);
+                },
+                SudiceCode::Load(slot) => {
+                    s.push(mem::replace(&mut tos, SudiceValue::Scalar(vars[slot])));
+                },
+                SudiceCode::Tuple(n) => {
+                    tuple.clear();
+                    tuple.push(collapse(mem::replace(&mut tos, SudiceValue::Scalar(0)), spare));
+                    for _ in 1..n {
+                        tuple.push(collapse(s.pop().unwrap(), spare));
+                    }
+                },
+                SudiceCode::Given => rejected = collapse(s.pop().unwrap(), spare) != 1,
+                SudiceCode::Lt => op2!(|x, y| from_bool(x < y)),
+                SudiceCode::Gt => op2!(|x, y| from_bool(x > y)),
+                SudiceCode::Eq => op2!(|x, y| from_bool(x == y)),
+                SudiceCode::Ne => op2!(|x, y| from_bool(x != y)),
+                SudiceCode::And => op2!(|x, y| from_bool(x == 1 && y == 1)),
+                SudiceCode::Or => op2!(|x, y| from_bool(x == 1 || y == 1)),
+                SudiceCode::Xor => op2!(|x, y| from_bool((x == 1) != (y == 1))),
+                SudiceCode::Not => op1!(|x| from_bool(x != 1)),
+                SudiceCode::Count => op1!(|x| (x == 1) as i64),
+                SudiceCode::Abs => op1!(i64::saturating_abs),
+                SudiceCode::Neg => op1!(i64::saturating_neg),
             }
-        }}
+            dcp += 1;
+        }
+        for v in s.drain(..) {
+            let _ = collapse(v, spare);
+        }
+        self.label = labels.last().cloned();
+        self.rejected = rejected;
+        collapse(tos, spare)
     }
+}
+
+// Works out which rolls produce dice that are only ever summed, as opposed
+// to being rerolled, dropped or capped first.
+fn summed_rolls(d: &SudiceExpression) -> Vec<bool> {
+    let mut summed = vec![true; d.code.len()];
+    let mut stack = Vec::with_capacity(d.code.len());
+    summed_rolls_with(d, 0, false, &mut stack, &mut summed);
+    summed
+}
+
+// Each stack entry is every roll whose dice that value may be, since a
+// select or a single repeat passes on the dice of whichever run it took.
+fn summed_rolls_with(d: &SudiceExpression, start: usize, until_jump: bool, stack: &mut Vec<Vec<usize>>, summed: &mut [bool]) {
+    let mut dcp = start;
     while dcp < d.code.len() {
         match d.code[dcp] {
-            SudiceCode::Num(i) => {
-                s.push(tos);
-                tos = SudiceValue::new(i);
+            SudiceCode::Num(_) | SudiceCode::Bool(_) | SudiceCode::Load(_) => stack.push(Vec::new()),
+            SudiceCode::RollConst(..) => stack.push(vec![dcp]),
+            SudiceCode::Roll => {
+                stack.truncate(stack.len() - 2);
+                stack.push(vec![dcp]);
+            },
+            SudiceCode::Reroll | SudiceCode::RerollLowest | SudiceCode::RerollHighest |
+            SudiceCode::RerollBelow | SudiceCode::RerollAbove | SudiceCode::RerollUntil |
+            SudiceCode::RerollBelowUntil | SudiceCode::RerollAboveUntil |
+            SudiceCode::DropLowest | SudiceCode::DropHighest |
+            SudiceCode::Ceil | SudiceCode::Floor | SudiceCode::Highest => {
+                for roll in stack.pop().unwrap() {
+                    summed[roll] = false;
+                }
+                let _ = stack.pop().unwrap();
+                stack.push(Vec::new());
+            },
+            SudiceCode::Largest | SudiceCode::Pairs | SudiceCode::Longest | SudiceCode::Unique => {
+                for roll in stack.pop().unwrap() {
+                    summed[roll] = false;
+                }
+                stack.push(Vec::new());
+            },
+            SudiceCode::BestOf(_) | SudiceCode::WorstOf(_) => {
+                let run = stack.pop().unwrap();
+                let _ = stack.pop().unwrap();
+                stack.push(run);
             },
-            SudiceCode::Add => op2!(SudiceValue::add),
-            SudiceCode::Sub => op2!(SudiceValue::sub),
-            SudiceCode::Mul => op2!(SudiceValue::mul),
-            SudiceCode::Div => op2!(SudiceValue::div),
-            SudiceCode::Roll => rop!(SudiceValue::roll),
-            SudiceCode::Reroll => rop!(SudiceValue::reroll),
-            SudiceCode::RerollLowest => rop!(SudiceValue::reroll_lowest),
-            SudiceCode::RerollHighest => rop!(SudiceValue::reroll_highest),
-            SudiceCode::DropLowest => op2!(SudiceValue::drop_lowest),
-            SudiceCode::DropHighest => op2!(SudiceValue::drop_highest),
-            SudiceCode::Ceil => op2!(SudiceValue::ceil),
-            SudiceCode::Floor => op2!(SudiceValue::floor),
-            SudiceCode::BestOf(offset) => accum!(cmp::max, offset),
-            SudiceCode::WorstOf(offset) => accum!(cmp::min, offset),
             SudiceCode::Select(ref offsets) => {
-                let t = tos.collapse();
-                let x = t - 2;
-                let len = offsets.len();
-                if x >= 0 && x < (len-2) as i64 {
-                    dcp += offsets[x as usize];
-                    tos = s.pop().unwrap();
-                } else if t == 1 {
-                    tos = s.pop().unwrap();
-                } else {
-                    dcp += offsets[len-2];
-                    tos = s.pop().unwrap();
+                let _ = stack.pop().unwrap();
+                let mut rolls = Vec::new();
+                let mut branch = dcp + 1;
+                for offset in offsets {
+                    summed_rolls_with(d, branch, true, stack, summed);
+                    rolls.extend(stack.pop().unwrap());
+                    branch = dcp + offset + 1;
                 }
+                stack.push(rolls);
+                dcp += offsets[offsets.len()-1];
+            },
+            SudiceCode::Label(_) => {},
+            SudiceCode::Bind(..) => {
+                let _ = stack.pop().unwrap();
+            },
+            SudiceCode::Tuple(n) => {
+                stack.truncate(stack.len() - n);
+                stack.push(Vec::new());
+            },
+            SudiceCode::Jump(_) => if until_jump {
+                return;
+            } else {
+                panic!("Error: Illegal bytecode sequence: Should not reach jump!");
+            },
+            SudiceCode::Not | SudiceCode::Count | SudiceCode::Abs | SudiceCode::Neg => {
+                let _ = stack.pop().unwrap();
+                stack.push(Vec::new());
+            },
+            _ => {
+                stack.truncate(stack.len() - 2);
+                stack.push(Vec::new());
             },
-            SudiceCode::Jump(offset) => dcp += offset,
-            SudiceCode::Lt => op2!(SudiceValue::lt),
-            SudiceCode::Gt => op2!(SudiceValue::gt),
-            SudiceCode::Eq => op2!(SudiceValue::eq),
-            SudiceCode::Ne => op2!(SudiceValue::ne),
-            SudiceCode::And => op2!(SudiceValue::and),
-            SudiceCode::Or => op2!(SudiceValue::or),
-            SudiceCode::Abs => tos = tos.abs(),
-            SudiceCode::Neg => tos = tos.neg(),
         }
         dcp += 1;
     }
-    tos.collapse()
 }
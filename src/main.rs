@@ -5,34 +5,498 @@ extern crate rand;
 
 mod descriptor;
 mod parser;
+mod typeck;
 mod checker;
 mod interpreter;
 mod estimator;
+mod disasm;
+mod optimizer;
+mod bench;
+mod compare;
+mod chart;
+mod export;
+mod png;
+mod fuzz;
+#[cfg(test)]
+mod exact;
 
+use checker::Support;
+use descriptor::{SudiceError, SudiceExpression};
+use estimator::SudiceResults;
 use parser::Rdp;
 
 use pest::prelude::*;
 
+use std::cmp;
+use std::env;
+use std::fs;
 use std::io::{self, Write};
+use std::process;
+use std::time::Duration;
+
+const DEFAULT_RANGE_CAP: u64 = 1 << 20;
+
+// Parses and compiles an expression, printing any syntax error with a
+// marker placed `indent` columns in from where the input was echoed.
+fn compile(input: &str, indent: usize) -> Option<SudiceExpression> {
+    let mut parser = Rdp::new(StringInput::new(input));
+    if !parser.expr() || !parser.end() {
+        let (rules, pos) = parser.expected();
+        println!("{marker:>width$}", marker = '^', width = pos + indent + 1);
+        print!("[Error] Expected one of: ");
+        for rule in rules.iter() {
+            print!("{:?} ", rule);
+        }
+        println!();
+        return None;
+    }
+    match parser.compile() {
+        Ok(code) => Some(code),
+        Err(e) => {
+            report(&e, input, indent);
+            None
+        },
+    }
+}
+
+// Underlines the part of the input an error refers to, which was echoed
+// `indent` columns in.
+fn report(e: &SudiceError, source: &str, indent: usize) {
+    let width = cmp::max(e.span.end - e.span.start, 1);
+    println!("{:>pad$}{}", "", "^".repeat(width), pad = e.span.start + indent);
+    println!("Error in `{}`: {}", e.span.excerpt(source), e);
+}
+
+struct Settings {
+    // Largest number of distinct outcomes an expression may have before
+    // sampling it is refused, bounding the size of its histogram.
+    range_cap: u64,
+    // How `:plot` draws its charts.
+    chart: chart::Style,
+}
+
+impl Settings {
+    fn new() -> Settings {
+        Settings { range_cap: DEFAULT_RANGE_CAP, chart: chart::Style::new() }
+    }
+}
+
+fn check(code: &SudiceExpression, indent: usize, settings: &Settings) -> Option<Support> {
+    let result = checker::semantic_check(code)
+        .and_then(|support| checker::range_check(code, &support, settings.range_cap).map(|_| support));
+    match result {
+        Ok(support) => Some(support),
+        Err(e) => {
+            report(&e, &code.source, indent);
+            None
+        },
+    }
+}
+
+// Checks an expression which may end in a tuple, giving the outcomes of
+// each of its components.
+fn check_joint(code: &SudiceExpression, indent: usize, settings: &Settings) -> Option<Vec<Support>> {
+    let result = checker::joint_check(code)
+        .and_then(|supports| checker::joint_range_check(code, &supports, settings.range_cap).map(|_| supports));
+    match result {
+        Ok(supports) => Some(supports),
+        Err(e) => {
+            report(&e, &code.source, indent);
+            None
+        },
+    }
+}
+
+// Splits a line into the expressions separated by semicolons in it, each
+// with the column it starts at when the line is echoed `indent` columns in.
+fn split_exprs(line: &str, indent: usize) -> Vec<(&str, usize)> {
+    let mut exprs = Vec::new();
+    let mut offset = indent;
+    for part in line.split(';') {
+        exprs.push((part.trim(), offset + part.len() - part.trim_start().len()));
+        offset += part.len() + 1;
+    }
+    exprs
+}
+
+// Estimates each of a number of expressions, at least `min` of them, as
+// split out of a line by `split_exprs`.
+fn estimate_each(exprs: &[(&str, usize)], min: usize, settings: &Settings) -> Option<Vec<(String, SudiceResults)>> {
+    if exprs.len() < min || exprs.len() > 26 {
+        println!("[Error] Expected between {} and 26 expressions.", min);
+        return None;
+    }
+    let mut results = Vec::new();
+    for &(expr, indent) in exprs {
+        let code = compile(expr, indent)?;
+        let support = check(&code, indent, settings)?;
+        let r = estimator::estimate(&optimizer::optimize(&code), &support);
+        if r.total == 0 {
+            println!("[Error] No sample of `{}` met its condition.", expr);
+            return None;
+        }
+        if r.unreliable() {
+            println!("[Warning] Only {:.2}% of samples of `{}` met its condition, too few to be reliable.", r.acceptance() * 100.0, expr);
+        }
+        results.push((expr.to_string(), r));
+    }
+    Some(results)
+}
+
+// Applies each word of `args` to a chart style: an orientation, a layout
+// or a width.
+fn restyle(style: &mut chart::Style, args: &[&str]) -> Result<(), String> {
+    for &arg in args {
+        match arg {
+            "horizontal" => style.orientation = chart::Orientation::Horizontal,
+            "vertical" => style.orientation = chart::Orientation::Vertical,
+            "overlay" => style.layout = chart::Layout::Overlay,
+            "interleave" => style.layout = chart::Layout::Interleave,
+            _ => match arg.parse::<usize>() {
+                Ok(width) if width > 0 => style.width = width,
+                _ => return Err(format!("Unknown chart setting `{}`.", arg)),
+            },
+        }
+    }
+    Ok(())
+}
+
+// Charts expressions into a file, as SVG or PNG according to its extension,
+// after applying each of `words` to the figure: a kind of chart or a mode.
+fn export(path: &str, words: &[&str], exprs: &[(&str, usize)], settings: &Settings) -> bool {
+    let (mut kind, mut mode) = (export::Kind::Bar, export::Mode::Normal);
+    for &word in words {
+        match word {
+            "bar" => kind = export::Kind::Bar,
+            "line" => kind = export::Kind::Line,
+            "normal" => mode = export::Mode::Normal,
+            "atleast" => mode = export::Mode::AtLeast,
+            "atmost" => mode = export::Mode::AtMost,
+            _ => {
+                println!("[Error] Unknown chart setting `{}`.", word);
+                return false;
+            },
+        }
+    }
+    let svg = path.ends_with(".svg");
+    if !svg && !path.ends_with(".png") {
+        println!("[Error] Expected a file name ending in .svg or .png.");
+        return false;
+    }
+    let series = match estimate_each(exprs, 1, settings) {
+        Some(series) => series,
+        None => return false,
+    };
+    let figure = export::Figure { series: &series, kind, mode };
+    let written = if svg { fs::write(path, figure.to_svg()) } else { fs::write(path, figure.to_png()) };
+    match written {
+        Ok(()) => {
+            println!("Wrote {}", path);
+            true
+        },
+        Err(e) => {
+            println!("[Error] Could not write {}: {}", path, e);
+            false
+        },
+    }
+}
+
+fn repl(settings: &mut Settings) -> io::Result<bool> {
+    print!(">>> ");
+    io::stdout().flush()?;
+    let mut buffer = String::new();
+    let n = io::stdin().read_line(&mut buffer)?;
+    let line = buffer.trim();
+    if line == "quit" || (line.is_empty() && n == 0) {
+        return Ok(true);
+    }
+
+    if let Some(rest) = line.strip_prefix(":code") {
+        let expr = rest.trim_start();
+        if let Some(code) = compile(expr, 4 + line.len() - expr.len()) {
+            print!("{}", disasm::disassemble(&code));
+        }
+        return Ok(false);
+    }
+
+    if let Some(rest) = line.strip_prefix(":opt") {
+        let expr = rest.trim_start();
+        let indent = 4 + line.len() - expr.len();
+        if let Some(code) = compile(expr, indent) {
+            if check_joint(&code, indent, settings).is_some() {
+                print!("{}", disasm::disassemble(&optimizer::optimize(&code)));
+            }
+        }
+        return Ok(false);
+    }
+
+    if let Some(rest) = line.strip_prefix(":cmp") {
+        if let Some(results) = estimate_each(&split_exprs(rest, 4 + line.len() - rest.len()), 2, settings) {
+            println!("{}", compare::Comparison(results));
+        }
+        return Ok(false);
+    }
+
+    if let Some(rest) = line.strip_prefix(":plot") {
+        if let Some(results) = estimate_each(&split_exprs(rest, 4 + line.len() - rest.len()), 1, settings) {
+            print!("{}", chart::Chart { series: &results, style: settings.chart });
+        }
+        return Ok(false);
+    }
+
+    if let Some(rest) = line.strip_prefix(":export") {
+        match rest.find("--") {
+            Some(split) => {
+                let words: Vec<&str> = rest[..split].split_whitespace().collect();
+                let exprs = &rest[split + 2..];
+                if let Some((path, words)) = words.split_first() {
+                    export(path, words, &split_exprs(exprs, 4 + line.len() - exprs.len()), settings);
+                    return Ok(false);
+                }
+                println!("[Error] Expected a file name to export to.");
+            },
+            None => println!("[Error] Expected `--` before the expressions to export."),
+        }
+        return Ok(false);
+    }
+
+    if let Some(rest) = line.strip_prefix(":chart") {
+        let args: Vec<&str> = rest.split_whitespace().collect();
+        let mut style = settings.chart;
+        match restyle(&mut style, &args) {
+            Ok(()) => settings.chart = style,
+            Err(e) => println!("[Error] {}", e),
+        }
+        println!("Chart: {}", settings.chart);
+        return Ok(false);
+    }
+
+    if let Some(rest) = line.strip_prefix(":cap") {
+        match rest.trim().parse::<u64>() {
+            Ok(cap) if cap > 0 => settings.range_cap = cap,
+            _ if rest.trim().is_empty() => {},
+            _ => println!("[Error] Expected a positive number of outcomes."),
+        }
+        println!("Range cap: {}", settings.range_cap);
+        return Ok(false);
+    }
+
+    if let Some(code) = compile(line, 4) {
+        if let Some(supports) = check_joint(&code, 4, settings) {
+            let optimized = optimizer::optimize(&code);
+            match typeck::type_check(&code) {
+                Ok(typeck::SudiceType::Tuple(_)) => println!("{}", estimator::estimate_joint(&optimized, &supports)),
+                Ok(typeck::SudiceType::Boolean) => println!("{}", estimator::Truth(&estimator::estimate(&optimized, &supports[0]))),
+                _ => println!("{}", estimator::estimate(&optimized, &supports[0])),
+            }
+        }
+    }
+    Ok(false)
+}
+
+fn usage() -> ! {
+    println!("Usage: sudice                  Start the interactive REPL");
+    println!("       sudice disasm <expr>    Print the bytecode for an expression");
+    println!("       sudice compare <expr> <expr>...");
+    println!("                               Estimate several expressions and compare them");
+    println!("       sudice plot [horizontal|vertical] [overlay|interleave] [<width>] -- <expr>...");
+    println!("                               Chart the distributions of several expressions");
+    println!("       sudice export <file> [bar|line] [normal|atleast|atmost] -- <expr>...");
+    println!("                               Write a chart of several expressions to an SVG or");
+    println!("                               PNG file");
+    println!("       sudice bench [--seconds <n>] [--baseline <report>]");
+    println!("                               Time a set of expressions, printing a report which");
+    println!("                               later runs can be compared against");
+    println!("       sudice fuzz [--seed <n>] [--iterations <n>]");
+    println!("                               Feed mutated expressions through every stage,");
+    println!("                               stopping at the first which panics");
+    process::exit(2);
+}
+
+// Prints a benchmark report, and exits with an error if any expression now
+// samples slower than in the baseline report by more than the tolerance.
+fn bench(args: &[String]) -> ! {
+    let mut duration = Duration::from_secs(1);
+    let mut baseline = None;
+    let mut i = 0;
+    while i < args.len() {
+        match (args[i].as_str(), args.get(i + 1)) {
+            ("--seconds", Some(n)) => match n.parse::<f64>().map(Duration::try_from_secs_f64) {
+                Ok(Ok(n)) if n > Duration::ZERO => duration = n,
+                _ => usage(),
+            },
+            ("--baseline", Some(path)) => baseline = Some(path),
+            _ => usage(),
+        }
+        i += 2;
+    }
+    let baseline = baseline.map(|path| {
+        match fs::read_to_string(path).map_err(|e| e.to_string()).and_then(|text| bench::Report::parse(&text)) {
+            Ok(report) => report,
+            Err(e) => {
+                eprintln!("[Error] Could not read {}: {}", path, e);
+                process::exit(1);
+            },
+        }
+    });
+
+    let report = bench::Report(bench::EXPRESSIONS.iter().map(|expr| bench::run(expr, duration)).collect());
+    print!("{}", report);
+
+    let mut regressed = false;
+    if let Some(baseline) = baseline {
+        for (expr, change, regression) in report.compare(&baseline) {
+            eprintln!("{:>+7.1}%  {}{}", change * 100.0, expr, if regression { "  [REGRESSION]" } else { "" });
+            regressed |= regression;
+        }
+    }
+    process::exit(if regressed { 1 } else { 0 });
+}
+
+fn fuzz(args: &[String]) -> ! {
+    let mut seed = 0;
+    let mut iterations = 100000;
+    let mut i = 0;
+    while i < args.len() {
+        match (args[i].as_str(), args.get(i + 1).map(|n| n.parse::<usize>())) {
+            ("--seed", Some(Ok(n))) => seed = n,
+            ("--iterations", Some(Ok(n))) => iterations = n,
+            _ => usage(),
+        }
+        i += 2;
+    }
+    match fuzz::fuzz(seed, iterations) {
+        Some(input) => {
+            println!("Panicked on input: {:?}", input);
+            process::exit(1);
+        },
+        None => {
+            println!("No panics in {} inputs.", iterations);
+            process::exit(0);
+        },
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        match (args[0].as_str(), args.len()) {
+            ("disasm", 2) => {
+                println!("{}", args[1]);
+                if let Some(code) = compile(&args[1], 0) {
+                    print!("{}", disasm::disassemble(&code));
+                    return;
+                }
+                process::exit(1);
+            },
+            ("compare", _) => {
+                let line = args[1..].join(" ; ");
+                println!("{}", line);
+                match estimate_each(&split_exprs(&line, 0), 2, &Settings::new()) {
+                    Some(results) => print!("{}", compare::Comparison(results)),
+                    None => process::exit(1),
+                }
+                return;
+            },
+            ("plot", _) => {
+                let split = args.iter().position(|a| a == "--").unwrap_or_else(|| usage());
+                let words: Vec<&str> = args[1..split].iter().map(|a| a.as_str()).collect();
+                let mut style = chart::Style::new();
+                if let Err(e) = restyle(&mut style, &words) {
+                    println!("[Error] {}", e);
+                    usage();
+                }
+                let line = args[split + 1..].join(" ; ");
+                println!("{}", line);
+                match estimate_each(&split_exprs(&line, 0), 1, &Settings::new()) {
+                    Some(results) => print!("{}", chart::Chart { series: &results, style }),
+                    None => process::exit(1),
+                }
+                return;
+            },
+            ("export", n) if n > 2 => {
+                // The file comes before any settings and the `--`.
+                let split = match args.iter().position(|a| a == "--") {
+                    Some(split) if split >= 2 => split,
+                    _ => usage(),
+                };
+                let words: Vec<&str> = args[2..split].iter().map(|a| a.as_str()).collect();
+                let line = args[split + 1..].join(" ; ");
+                println!("{}", line);
+                if !export(&args[1], &words, &split_exprs(&line, 0), &Settings::new()) {
+                    process::exit(1);
+                }
+                return;
+            },
+            ("bench", _) => bench(&args[1..]),
+            ("fuzz", _) => fuzz(&args[1..]),
+            _ => usage(),
+        }
+    }
+
+    println!("Sudice [v 0.5.0]");
+    println!("-- Dice Expression Language");
+    let mut settings = Settings::new();
+    loop {
+        match repl(&mut settings) {
+            Ok(quit) => if quit { return; },
+            Err(e) => {
+                println!("{}", e);
+                return
+            }
+        }
+    }
+}
 
 #[cfg(test)]
 mod test {
     use parser::Rdp;
     use checker;
     use estimator;
-    use pest::prelude::StringInput;
+    use disasm;
+    use optimizer;
+    use bench;
+    use compare;
+    use chart;
+    use export;
+    use exact;
+    use fuzz;
+    use typeck::{self, SudiceType};
+    use estimator::SudiceResults;
+    use interpreter::Interpreter;
+    use rand::{Rng, SeedableRng, StdRng};
+    use descriptor::{self, SudiceCode, SudiceExpression};
+    use pest::prelude::*;
+
+    // Every test which samples and then looks at the results draws from the
+    // same fixed seed, so that none of them can fail by chance.
+    fn seeded() -> StdRng {
+        StdRng::from_seed(&[0x5d1ce][..])
+    }
+
+    // Parses and compiles an expression, which must be well formed.
+    fn compile(expr: &str) -> SudiceExpression {
+        let mut parser = Rdp::new(StringInput::new(expr));
+        assert!(parser.expr() && parser.end(), "{} should parse", expr);
+        parser.compile().unwrap()
+    }
+
+    // Checks an expression and samples its optimized code.
+    fn estimate(expr: &str) -> SudiceResults {
+        let code = compile(expr);
+        let support = checker::semantic_check(&code).unwrap();
+        estimator::estimate_with(&optimizer::optimize(&code), &support, &mut seeded())
+    }
 
     // Test helper function.
     //
     // Do not use any expr with an EV/SD of 0 for testing since results
     // are based on percent error off actual EV/SD.
     fn check_expr(expr: &'static str, ev: f64, sd: f64, range: i64) {
-        let mut parser = Rdp::new(StringInput::new(expr));
-        assert!(parser.expr());
-        let code = parser.compile();
+        let code = compile(expr);
         match checker::semantic_check(&code) {
-            Ok((min, max)) => {
-                let results = estimator::estimate(&code, min, max);
+            Ok(support) => {
+                let results = estimator::estimate_with(&optimizer::optimize(&code), &support, &mut seeded());
                 assert!(results.max - results.min + 1 == range);
                 if ev < 0.0 {
                     assert!(results.ev >= ev * 1.02 && results.ev <= ev * 0.98);
@@ -57,6 +521,9 @@ mod test {
         check_expr("3 - 7", -4.0, 0.0, 1);
         check_expr("3 * 7", 21.0, 0.0, 1);
         check_expr("21 / 7", 3.0, 0.0, 1);
+        check_expr("23 % 7", 2.0, 0.0, 1);
+        check_expr("2 ** 3 ** 2", 512.0, 0.0, 1);
+        check_expr("max(3, 7) - min(3, 7)", 4.0, 0.0, 1);
     }
 
     #[test]
@@ -77,54 +544,824 @@ mod test {
     fn rolls_with_drop() {
         check_expr("3d6\\h1", 5.54, 2.215, 11);
         check_expr("4d6\\l1", 12.24, 2.847, 16);
+        check_expr("4d6\\h1\\l1", 7.0, 2.227, 11);
     }
 
     #[test]
     fn rolls_with_iteration() {
         check_expr("1d20b2", 13.82, 4.71, 20);
         check_expr("1d20w2", 7.17, 4.71, 20);
+        check_expr("(1d6)s3", 10.5, 2.958, 16);
+        check_expr("(1d6)c3\\l1", 8.458, 2.213, 11);
+        // A single run is passed on as it is, so capping it caps each die,
+        // while the best of several is summed before it is capped.
+        check_values("(3d6)b1^4", &[3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+        check_values("(3d6)w1^4", &[3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+        check_values("(3d6)b2^4", &[3, 4]);
     }
-}
 
-fn repl() -> io::Result<bool> {
-    print!(">>> ");
-    let _ = io::stdout().flush()?;
-    let mut buffer = String::new();
-    let n = io::stdin().read_line(&mut buffer)?;
-    if buffer.trim() == "quit" || (buffer.trim() == "" && n == 0) {
-        return Ok(true);
+    #[test]
+    fn source_spans() {
+        let code = compile("2 + 3d6\\l4");
+        assert_eq!(code.code.len(), code.spans.len());
+        let excerpts: Vec<&str> = code.spans.iter().map(|s| s.excerpt(&code.source)).collect();
+        assert_eq!(excerpts, vec!["4", "6", "3", "3d6", "3d6\\l4", "2", "2 + 3d6\\l4"]);
+        match checker::semantic_check(&code) {
+            Ok(_) => panic!("Semantic check should have failed."),
+            Err(e) => assert_eq!(e.span.excerpt(&code.source), "3d6\\l4"),
+        }
     }
 
-    let mut parser = Rdp::new(StringInput::new(&buffer.trim()[..]));
-    if !parser.expr() || !parser.end() {
-        let (rules, pos) = parser.expected();
-        println!("{marker:>width$}", marker = '^', width = pos + 5);
-        print!("[Error] Expected one of: ");
-        for rule in rules.iter() {
-            print!("{:?} ", rule);
-        }
-        println!();
-        return Ok(false);
+    #[test]
+    #[should_panic(expected = "Error in `3d6\\l4`: Cannot drop 4 from 3 rolls.")]
+    fn runtime_spans() {
+        // Sampling what the checker refused fails against the same text.
+        let code = compile("2 + 3d6\\l4");
+        Interpreter::new(&code).sample(&mut StdRng::from_seed(&[0][..]));
     }
 
-    let code = parser.compile();
-    match checker::semantic_check(&code) {
-        Ok((min, max)) => println!("{}", estimator::estimate(&code, min, max)),
-        Err(s) => println!("Error: {}", s),
+    #[test]
+    fn disassembly() {
+        let code = compile("[1d2 ? 3 : 4] + 1");
+        let text = disasm::disassemble(&code).to_string();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), code.code.len() + 1);
+        assert!(lines[5].contains("Select 1:5 _:7 end:9"));
+        assert!(lines[7].contains("Jump -> 9"));
+        assert!(lines[10].trim_start().starts_with("9     1  Add"));
     }
-    Ok(false)
-}
 
-fn main() {
-    println!("Sudice [v 0.5.0]");
-    println!("-- Dice Expression Language");
-    loop {
-        match repl() {
-            Ok(quit) => if quit { return; },
+    fn check_error(expr: &'static str, excerpt: &'static str, message: &'static str) {
+        let code = compile(expr);
+        match checker::semantic_check(&code) {
+            Ok(_) => panic!("Semantic check of {} should have failed.", expr),
             Err(e) => {
-                println!("{}", e);
-                return
+                assert_eq!(e.span.excerpt(&code.source), excerpt);
+                assert_eq!(e.message, message);
+            },
+        }
+    }
+
+    #[test]
+    fn type_errors() {
+        check_error("(1d6+1)rr1", "(1d6+1)rr1", "Attempted to reroll scalar.");
+        check_error("(3d20+2)\\l1", "(3d20+2)\\l1", "Attempted to drop scalar.");
+        check_error("1d20b2rh1", "1d20b2rh1", "Attempted to reroll scalar.");
+        check_error("(1d6)c3rl1", "(1d6)c3rl1", "Attempted to reroll collected results.");
+        check_error("[1 ? 2 : 1 + (-1d6)rl1]", "(-1d6)rl1", "Attempted to reroll scalar.");
+    }
+
+    // Checks the exact bounds computed for an expression, then samples it,
+    // which panics should any outcome fall outside of them.
+    fn check_bounds(expr: &'static str, min: i64, max: i64) {
+        let code = compile(expr);
+        match checker::semantic_check(&code) {
+            Ok(support) => {
+                assert_eq!((support.min(), support.max()), (min, max), "bounds of {}", expr);
+                let _ = estimator::estimate(&code, &support);
+            },
+            Err(e) => panic!("Semantic check of {} failed: {}", expr, e),
+        }
+    }
+
+    #[test]
+    fn sign_intervals() {
+        check_bounds("(1d6-3)*(1d6-3)", -6, 9);
+        check_bounds("(1d6-3)*(1d6-4)", -9, 6);
+        check_bounds("-1d6*1d6", -36, -1);
+        check_bounds("(1d6-7)*(1d6-7)", 1, 36);
+        check_bounds("(1d6-3)/1d2", -2, 3);
+        check_bounds("100/(1d6-7)", -100, -17);
+        check_bounds("(1d6-7)/(1d6-7)", 0, 6);
+        check_bounds("(1d6-3)/(0-2)", -2, 1);
+        check_bounds("1d1000 / ((1d2*2-3)*1d20)", -1000, 1000);
+        check_bounds("1d6-1d6", -5, 5);
+        check_bounds("|1d6-4|", 0, 3);
+        check_bounds("|1d6-7|", 1, 6);
+        check_bounds("1d20^5-1d20_15", -19, -10);
+        check_bounds("(1d2)d6^-1", -2, -1);
+        check_bounds("(1d3-1)d6", 0, 12);
+        check_error("(1d6-3)/(1d6-3)", "(1d6-3)/(1d6-3)", "Attempted to divide by a range containing zero.");
+        check_error("1d6/(1d3-1)", "1d6/(1d3-1)", "Attempted to divide by a range containing zero.");
+        check_error("3d6\\l(1d3-2)", "3d6\\l(1d3-2)", "Attempted to drop negative amount of dice.");
+
+        // Too wide to sample here, but the divisors of -1 and 1 nearest zero
+        // must still be found.
+        let support = checker::semantic_check(&compile("1d100000 / ((1d2*2-3)*1d20)")).unwrap();
+        assert_eq!((support.min(), support.max()), (-100000, 100000));
+    }
+
+    #[test]
+    fn overflow() {
+        check_error("1000000d1000000*1000000000", "1000000d1000000*1000000000", "Bounds of this expression overflow 64-bit integers.");
+        check_error("1 + -(4611686018427387904*2)", "4611686018427387904*2", "Bounds of this expression overflow 64-bit integers.");
+        check_error("|1d2 - 1 - 9223372036854775807 - 1|", "|1d2 - 1 - 9223372036854775807 - 1|", "Bounds of this expression overflow 64-bit integers.");
+        check_bounds("1d2 - 9223372036854775807 - 1", -9223372036854775807, -9223372036854775806);
+
+        let code = compile("1000d1000000*1000000");
+        let support = checker::semantic_check(&code).unwrap();
+        assert_eq!((support.min(), support.max()), (1000000000, 1000000000000000));
+        assert!(checker::range_check(&code, &support, 1 << 20).is_err());
+        let small = checker::Support::Range(checker::Interval { min: -3, max: 3 });
+        assert!(checker::range_check(&code, &small, 7).is_ok());
+    }
+
+    #[test]
+    fn division_rounding() {
+        for x in -20..=20 {
+            for y in (-6..=6).filter(|&y| y != 0) {
+                let q = x as f64 / y as f64;
+                assert_eq!(descriptor::checked_div_floor(x, y), Some(q.floor() as i64), "{} / {}", x, y);
+                assert_eq!(descriptor::checked_div_ceil(x, y), Some(q.ceil() as i64), "{} /^ {}", x, y);
+                assert_eq!(descriptor::checked_div_round(x, y), Some((q + 0.5).floor() as i64), "{} /~ {}", x, y);
+                // What `%` leaves over is what rounding down took off.
+                assert_eq!(q.floor() as i64 * y + descriptor::checked_rem_floor(x, y).unwrap(), x);
+            }
+        }
+        assert_eq!(descriptor::checked_div_round(i64::MIN, -1), None);
+        assert_eq!(descriptor::checked_div_round(i64::MAX, i64::MIN), Some(-1));
+        assert_eq!(descriptor::checked_div_round(i64::MIN, i64::MAX), Some(-1));
+
+        check_values("(1d6 - 3) / 2", &[-1, 0, 1]);
+        check_values("(1d6 - 3) /^ 2", &[-1, 0, 1, 2]);
+        check_values("(1d6 - 3) /~ 2", &[-1, 0, 1, 2]);
+        check_values("1d6 /~ (1d2 - 3)", &[-6, -5, -4, -3, -2, -1, 0]);
+        check_expr("(-7) / 2", -4.0, 0.0, 1);
+        // A leading minus negates the whole quotient, which rounded down.
+        check_expr("-7 / 2", -3.0, 0.0, 1);
+        check_expr("(-7) /^ 2", -3.0, 0.0, 1);
+        check_expr("8d6 /~ 2", 14.25, 2.428, 21);
+        check_error("1d6 /^ (1d3 - 2)", "1d6 /^ (1d3 - 2)", "Attempted to divide by a range containing zero.");
+        check_error("(0 - 9223372036854775807 - 1) /~ -1", "(0 - 9223372036854775807 - 1) /~ -1", "Bounds of this expression overflow 64-bit integers.");
+    }
+
+    #[test]
+    fn modulo_and_powers() {
+        check_values("1d100 % 10", &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        check_values("-1d6 % 4", &[-3, -2, -1, 0]);
+        check_values("2 ** 1d3", &[2, 4, 8]);
+        check_values("(1d3 - 2) ** 2", &[0, 1]);
+        check_values("(1d6 - 3) ** 1d2", &[-2, -1, 0, 1, 2, 3, 4, 9]);
+        check_values("min(1d6, 3)", &[1, 2, 3]);
+        check_values("max(2d6, 10)", &[10, 11, 12]);
+        check_bounds("1d1000000 % 1000", 0, 999);
+        check_bounds("-1d1000000 % 7", -6, 0);
+        // The remainder takes the sign of the divisor, not the dividend.
+        check_expr("(-7) % 2", 1.0, 0.0, 1);
+        check_values("(0 - 1d6) % 4", &[0, 1, 2, 3]);
+        check_values("1d6 % (0 - 4)", &[-3, -2, -1, 0]);
+        check_values("(1d6 - 4) % (0 - 4)", &[-3, -2, -1, 0]);
+        check_bounds("(1d1000000 - 500000) % 7", 0, 6);
+        check_bounds("(500000 - 1d1000000) % (0 - 7)", -6, 0);
+        for x in -7..8 {
+            for y in [-3, -2, 2, 3] {
+                let r = descriptor::checked_rem_floor(x, y).unwrap();
+                assert!((x - r) % y == 0 && r * y >= 0 && r.abs() < y.abs());
+            }
+        }
+        check_values("2 ** 3 ** 2", &[512]);
+        check_values("(2 ** 3) ** 2", &[64]);
+        check_values("-2 ** 2", &[-4]);
+        check_values("(-2) ** 2", &[4]);
+        check_expr("1d6 ** 2", 15.17, 12.21, 36);
+        check_error("1d6 % (1d3 - 2)", "1d6 % (1d3 - 2)", "Attempted to divide by a range containing zero.");
+        check_error("1 + 2 ** (1d3 - 2)", "2 ** (1d3 - 2)", "Attempted to raise to a negative power.");
+        check_error("3 ** 2 ** (1d3 - 2)", "2 ** (1d3 - 2)", "Attempted to raise to a negative power.");
+        check_error("3 ** 40", "3 ** 40", "Bounds of this expression overflow 64-bit integers.");
+
+        // Supports too large to track exactly are bounded from their ends.
+        for &(expr, min, max) in &[
+            ("(1d100000 - 50001) ** 3", -125000000000000, 124992500149999),
+            ("(1d100000 - 50001) ** 1d2", -50000, 2500000000),
+            ("(1d100000 - 1) ** 0", 1, 1),
+            ("(1d100000 - 50001) ** 2", 0, 2500000000),
+            ("(-1) ** 1d100000", -1, 1),
+        ] {
+            let support = checker::semantic_check(&compile(expr)).unwrap();
+            assert_eq!((support.min(), support.max()), (min, max), "bounds of {}", expr);
+        }
+    }
+
+    #[test]
+    fn reroll_and_repeat_checks() {
+        check_error("1d20rl5", "1d20rl5", "Attempted to reroll too many dice.");
+        check_error("2d20rh(1d3)", "2d20rh(1d3)", "Attempted to reroll too many dice.");
+        check_error("3d6rl(1d2-2)", "3d6rl(1d2-2)", "Attempted to reroll negative amount of dice.");
+        check_error("1d20b0", "1d20b0", "Attempted to repeat an expression less than once.");
+        check_error("1 + 1d20w(1d3-1)", "1d20w(1d3-1)", "Attempted to repeat an expression less than once.");
+        check_error("1d6s0", "1d6s0", "Attempted to repeat an expression less than once.");
+        check_error("(1d6)c(1d2)\\l1", "(1d6)c(1d2)\\l1", "Attempted to drop too many values.");
+        check_bounds("(1d6-4)c3\\h1", -6, 4);
+        check_bounds("(1d6)c(1d3)^4", 1, 12);
+        check_bounds("1d20^10rr1", 1, 20);
+        check_bounds("4d6_3rl4", 4, 24);
+        check_bounds("(1d6-4)w3", -3, 2);
+        check_bounds("3d6^2\\h1rh2", 2, 12);
+        check_expr("3d6rl3", 10.5, 2.958, 16);
+        check_error("1d6r<7", "1d6r<7", "Attempted to reroll every face of the die.");
+        check_error("(1d3)d6r<(1d3+4)", "(1d3)d6r<(1d3+4)", "Attempted to reroll every face of the die.");
+        check_error("2d6r>(1d2-1)", "2d6r>(1d2-1)", "Attempted to reroll every face of the die.");
+        check_bounds("1d20r<3", 1, 20);
+        check_bounds("3d6r>(1d6)", 3, 18);
+        check_expr("1d20r<3", 11.4, 5.257, 20);
+        check_expr("1d20r>15", 8.625, 4.846, 20);
+        check_error("1d1rr!1", "1d1rr!1", "Attempted to reroll every face of the die.");
+        check_error("(1d2)d(1d3)rr!(1d2)", "(1d2)d(1d3)rr!(1d2)", "Attempted to reroll every face of the die.");
+        check_error("1d6r<!(1d2+5)", "1d6r<!(1d2+5)", "Attempted to reroll every face of the die.");
+        check_bounds("2d6rr!1", 2, 12);
+        check_expr("1d6rr!1", 4.0, 1.414, 6);
+        check_expr("1d20r<!3", 11.5, 5.188, 20);
+        check_expr("1d20r>!15", 8.0, 4.320, 20);
+    }
+
+    fn check_values(expr: &'static str, values: &[i64]) {
+        let code = compile(expr);
+        match checker::semantic_check(&code) {
+            Ok(support) => {
+                assert_eq!(support.values(), values, "values of {}", expr);
+                let results = estimator::estimate(&code, &support);
+                assert_eq!(results.values, values);
+            },
+            Err(e) => panic!("Semantic check of {} failed: {}", expr, e),
+        }
+    }
+
+    #[test]
+    fn support_sets() {
+        check_values("10*1d2", &[10, 20]);
+        check_values("1d3*1d3", &[1, 2, 3, 4, 6, 9]);
+        check_values("2d2*3-1", &[5, 8, 11]);
+        check_values("[1d2 == 1 ? 5 : 1d2*7]", &[5, 7, 14]);
+        check_values("(1d6-3)/(1d2*2-3)", &[-3, -2, -1, 0, 1, 2, 3]);
+        check_values("[1d2 ? 3d6 : 2d6]^4", &[2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+        check_values("(3d6b1)^4", &[3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+        check_values("(10*1d2)s2", &[20, 30, 40]);
+        check_values("(10*1d2)c3\\l2", &[10, 20]);
+        check_bounds("1d6*1d6", 1, 36);
+        check_expr("10*1d2", 15.0, 5.0, 11);
+    }
+
+    #[test]
+    fn pool_sets() {
+        check_bounds("largest(8d6)", 2, 8);
+        check_bounds("pairs(8d6)", 1, 4);
+        check_bounds("longest(3d20)", 1, 3);
+        check_bounds("longest(3d6_5)", 1, 2);
+        check_bounds("unique(10d6)", 1, 6);
+        check_bounds("unique((1d4)d6^3)", 1, 3);
+        check_bounds("highest(4d6, 1d2)", 0, 6);
+        check_bounds("largest((1d20 > 10)c5)", 3, 5);
+        check_values("highest(2d6, 3)", &[0]);
+        check_values("highest(3d2, 2)", &[1, 2]);
+        check_values("pairs(3d1)", &[1]);
+        check_error("unique(1d6 + 1)", "unique(1d6 + 1)", "Attempted to match faces of scalar.");
+        check_error("2 * highest(3d6, 1d2 - 1)", "highest(3d6, 1d2 - 1)", "Attempted to match sets of fewer than one die.");
+        check_expr("[1d2 ? 1d6 largest(3d6) : 0]", 2.486, 1.624, 6);
+    }
+
+    fn check_type(expr: &'static str, expected: SudiceType) {
+        let code = compile(expr);
+        assert_eq!(typeck::type_check(&code).unwrap(), expected, "type of {}", expr);
+    }
+
+    #[test]
+    fn booleans() {
+        check_values("true", &[1]);
+        check_values("not true", &[2]);
+        check_values("not 1d20 > 8", &[1, 2]);
+        check_values("1d2 == 1 xor true", &[1, 2]);
+        check_values("1d2 > 0 xor 1d2 > 0", &[2]);
+        check_values("#(1d20 > 8)", &[0, 1]);
+        check_values("#(1d20 > 20)", &[0]);
+        check_values("#1d2 + #false", &[1, 2]);
+        check_bounds("#(1d20 > 8) + #(1d20 > 8) + #(1d20 > 8)", 0, 3);
+        check_expr("#(1d20 > 8) + #(1d20 > 8)", 1.2, 0.6928, 3);
+        check_expr("#(1d20 > 10 xor 1d20 > 10) + 1", 1.5, 0.5, 2);
+
+        check_type("1d20 > 8 and not false", SudiceType::Boolean);
+        check_type("[1d2 ? true : 1d6 > 4]", SudiceType::Boolean);
+        check_type("[1d2 ? true : 1d6]", SudiceType::Scalar);
+        check_type("#(1d20 > 8)", SudiceType::Scalar);
+        check_type("(1d20 > 8)^1", SudiceType::Scalar);
+        check_type("3d6", SudiceType::Vector);
+        check_type("(1d20 > 8)c3^1", SudiceType::Collection);
+        check_type("(1d20 > 8)s3", SudiceType::Scalar);
+    }
+
+    // Checks that an expression optimizes to exactly `expected`, and that
+    // the optimized code produces the same outcomes just as often.
+    fn check_optimized(expr: &'static str, expected: &[SudiceCode]) {
+        let code = compile(expr);
+        let optimized = optimizer::optimize(&code);
+        assert_eq!(optimized.code, expected, "optimized code of {}", expr);
+        assert_eq!(optimized.code.len(), optimized.spans.len());
+        let before = checker::semantic_check(&code).unwrap();
+        let after = checker::semantic_check(&optimized).unwrap();
+        assert_eq!(before.values(), after.values(), "values of {}", expr);
+        let before = exact::distribution(&code, 1000).unwrap();
+        let after = exact::distribution(&optimized, 1000).unwrap();
+        assert_eq!(before.len(), after.len(), "optimizing {} changed its outcomes", expr);
+        for (&(x, p), &(y, q)) in before.iter().zip(after.iter()) {
+            assert!(x == y && (p - q).abs() < 1e-9, "optimizing {} changed P({}) from {} to {}", expr, x, p, q);
+        }
+    }
+
+    #[test]
+    fn optimizer() {
+        use descriptor::SudiceCode::*;
+        check_optimized("3 + 4 * 2 + 1d6", &[RollConst(1, 6), Num(11), Add]);
+        check_optimized("--1d6", &[RollConst(1, 6)]);
+        check_optimized("(--3d6)^4", &[Num(4), RollConst(3, 6), Neg, Neg, Ceil]);
+        check_optimized("[1 ? 3d6 : 1]^4", &[Num(4), RollConst(3, 6), Ceil]);
+        check_optimized("[1 ? 3d6 : 1]^4 + [2 ? 1 : 2d6]", &[RollConst(2, 6), Num(4), RollConst(3, 6), Ceil, Add]);
+        check_optimized("--(1d6+1)", &[Num(1), RollConst(1, 6), Add]);
+        check_optimized("(2*2)d(3+3)\\l1", &[Num(1), RollConst(4, 6), DropLowest]);
+        check_optimized("1d(1d6)", &[RollConst(1, 6), Num(1), Roll]);
+        check_optimized("[1 < 2 ? 1d4 : 1d8]", &[RollConst(1, 4)]);
+        check_optimized("[3 ? 1 2 3 : 1d8]", &[Num(3)]);
+        check_optimized("[7 ? 1 2 3 : 1d8]", &[RollConst(1, 8)]);
+        check_optimized("[1d2 ? 2+2 : 1d8]", &[RollConst(1, 2), Select(vec![2, 4]), Num(4), Jump(2), RollConst(1, 8), Jump(0)]);
+        check_optimized("(1+1)b(1d20)", &[Num(2)]);
+        check_optimized("1d20b(1+1)", &[Num(2), RollConst(1, 20), BestOf(1)]);
+        check_optimized("(1+2)s(2*2)", &[Num(12)]);
+        check_optimized("(1d6+2)c3\\l1", &[Num(1), Num(3), Num(2), RollConst(1, 6), Add, CollectOf(3), DropLowest]);
+        check_optimized("|1d2-3| == 2 and 3 > 1", &[Num(1), Num(2), Num(3), RollConst(1, 2), Sub, Abs, Eq, And]);
+        check_optimized("#(not false xor 2 > 1) + true", &[Num(1)]);
+        check_optimized("2 ** 3 % 5 + min(1d6, max(2, 4))", &[Num(4), RollConst(1, 6), Min, Num(3), Add]);
+
+        check_expr("2d(2+4)\\l(3-2) + 3*1", 7.472, 1.404, 6);
+        check_expr("[1d3 ? (1+1)d6 2*5 : --1d4]", 6.5, 3.444, 12);
+    }
+
+    #[test]
+    fn bench_reports() {
+        let result = |expr: &str, samples_per_sec| bench::BenchResult {
+            expr: expr.to_string(), parse_ns: 1000.0, check_ns: 500.0, samples_per_sec,
+        };
+        let baseline = bench::Report(vec![result("3d6", 1000.0), result("4d6\\l1", 1000.0), result("1d20b2", 1000.0)]);
+        let text = baseline.to_string();
+        assert_eq!(text.lines().nth(2), Some("4d6\\l1\t1000\t500\t1000"));
+        let baseline = bench::Report::parse(&text).unwrap();
+        assert_eq!(baseline.0.len(), 3);
+
+        let report = bench::Report(vec![result("3d6", 1200.0), result("4d6\\l1", 850.0), result("1d6", 10.0)]);
+        let changes = report.compare(&baseline);
+        assert_eq!(changes.len(), 2);
+        assert_eq!((changes[0].0.as_str(), changes[0].2), ("3d6", false));
+        assert_eq!((changes[1].0.as_str(), changes[1].2), ("4d6\\l1", true));
+        assert!((changes[1].1 + 0.15).abs() < 1e-9);
+
+        assert!(bench::Report::parse("3d6\t1\t2\t3\n").is_err());
+        assert!(bench::Report::parse("expr\tparse_ns\tcheck_ns\tsamples_per_sec\n3d6\t1\tx\t3\n").is_err());
+        for bad in &["0", "-3", "inf", "NaN"] {
+            let text = format!("expr\tparse_ns\tcheck_ns\tsamples_per_sec\n3d6\t1\t2\t{}\n", bad);
+            assert!(bench::Report::parse(&text).is_err(), "{} should be rejected", bad);
+        }
+        // However short the time given, something is run to time.
+        let result = bench::run("3d6", std::time::Duration::from_nanos(100));
+        assert!(result.parse_ns.is_finite() && result.check_ns.is_finite() && result.samples_per_sec > 0.0);
+    }
+
+    // Results as though `attempts` samples had fallen exactly as `dist`.
+    fn results(values: Vec<i64>, dist: Vec<f64>, attempts: usize) -> SudiceResults {
+        let hist = dist.iter().map(|p| (p * attempts as f64) as u64).collect();
+        SudiceResults::from_hist(values, hist, Vec::new())
+    }
+
+    #[test]
+    fn comparisons() {
+        // A fair coin worth 1 or 3 against a certain 2 never ties.
+        let a = results(vec![1, 3], vec![0.5, 0.5], 1000);
+        let b = results(vec![2], vec![1.0], 1000);
+        let o = compare::odds(&a, &b);
+        assert!((o.gt - 0.5).abs() < 1e-9 && o.eq == 0.0 && (o.lt - 0.5).abs() < 1e-9);
+
+        let c = results(vec![1, 2, 3], vec![0.25, 0.5, 0.25], 1000);
+        let o = compare::odds(&c, &a);
+        assert!((o.gt - 0.375).abs() < 1e-9 && (o.eq - 0.25).abs() < 1e-9 && (o.lt - 0.375).abs() < 1e-9);
+        let (diff, ci) = compare::mean_difference(&c, &a);
+        assert!(diff.abs() < 1e-9);
+        assert!((ci - 1.96 * (1.5f64 / 1000.0).sqrt()).abs() < 1e-9);
+
+        let text = compare::Comparison(vec![("1d2".to_string(), a), ("2".to_string(), b)]).to_string();
+        assert!(text.contains("P(A > B):\t50.00%"));
+        assert!(text.contains("P(A = B):\t0.00%"));
+        assert_eq!(text.lines().filter(|l| l.contains('|')).count(), 4);
+    }
+
+    #[test]
+    fn charts() {
+        let series = vec![
+            ("1d2".to_string(), results(vec![1, 2], vec![0.5, 0.5], 100)),
+            ("2".to_string(), results(vec![2], vec![1.0], 100)),
+        ];
+        let mut style = chart::Style::new();
+        style.width = 4;
+        let draw = |style| chart::Chart { series: &series, style }.to_string();
+        assert_eq!(draw(style), "# A: 1d2\n* B: 2\n1 |##\n2 |##**\n");
+
+        style.layout = chart::Layout::Interleave;
+        assert_eq!(draw(style), "# A: 1d2\n* B: 2\n1 |##\n  |\n2 |##\n  |****\n");
+
+        style.orientation = chart::Orientation::Vertical;
+        style.width = 2;
+        assert_eq!(draw(style), "# A: 1d2\n* B: 2\n    *\n#  #*\n------\n 1  2\n");
+
+        style.layout = chart::Layout::Overlay;
+        assert_eq!(draw(style), "# A: 1d2\n* B: 2\n  *\n# #\n----\n1 2\n");
+    }
+
+    #[test]
+    fn exports() {
+        let series = vec![
+            ("1d4 < 3".to_string(), results(vec![1, 2], vec![0.5, 0.5], 100)),
+            ("1d3".to_string(), results(vec![1, 2, 3], vec![0.25, 0.25, 0.5], 100)),
+        ];
+        let figure = |kind, mode| export::Figure { series: &series, kind, mode };
+        let (values, points) = figure(export::Kind::Bar, export::Mode::Normal).points();
+        assert_eq!(values, vec![1, 2, 3]);
+        assert_eq!(points[0], vec![0.5, 0.5, 0.0]);
+        assert_eq!(figure(export::Kind::Bar, export::Mode::AtLeast).points().1[1], vec![1.0, 0.75, 0.5]);
+        assert_eq!(figure(export::Kind::Bar, export::Mode::AtMost).points().1[1], vec![0.25, 0.5, 1.0]);
+
+        let svg = figure(export::Kind::Line, export::Mode::Normal).to_svg();
+        assert!(svg.starts_with("<svg ") && svg.ends_with("</svg>\n"));
+        assert!(svg.contains(">A: 1d4 &lt; 3</text>"));
+        assert!(svg.contains(">B: 1d3</text>"));
+        assert_eq!(svg.matches("<line ").count(), 5 + 3 + 2 * 2 + 2);
+
+        let png = figure(export::Kind::Bar, export::Mode::Normal).to_png();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..24], b"IHDR\x00\x00\x03\x20\x00\x00\x01\xe0");
+        // An empty chunk's checksum covers only its name, so is well known.
+        assert_eq!(&png[png.len() - 12..], b"\x00\x00\x00\x00IEND\xae\x42\x60\x82");
+        let (width, height) = (800usize, 480usize);
+        let raw = height * (1 + 3 * width);
+        let blocks = raw.div_ceil(0xffff);
+        assert_eq!(png.len(), 8 + (12 + 13) + (12 + 2 + 5 * blocks + raw + 4) + 12);
+
+        let mut labelled = results(vec![0, 1], vec![0.25, 0.75], 100);
+        labelled.labels.push(estimator::LabelResults { label: "hit".to_string(), share: 0.75, ev: 1.0 });
+        let series = vec![("[1d4 ? \"miss\": 0 : \"hit\": 1]".to_string(), labelled)];
+        let svg = export::Figure { series: &series, kind: export::Kind::Bar, mode: export::Mode::Normal }.to_svg();
+        assert!(svg.contains(">A: [1d4 ? \"miss\": 0 : \"hit\": 1] (hit 75.0%)</text>"));
+
+        // With no outcomes to mark, the axes are still drawn.
+        let svg = export::Figure { series: &[], kind: export::Kind::Bar, mode: export::Mode::Normal }.to_svg();
+        assert!(svg.ends_with("</svg>\n"));
+    }
+
+    #[test]
+    fn labels() {
+        use descriptor::SudiceCode::*;
+        let labels = estimate("[1d4 ? \"one\": 1 : \"more\": 1d6 + 1]").labels;
+        assert_eq!(labels.iter().map(|l| l.label.as_str()).collect::<Vec<_>>(), ["one", "more"]);
+        assert!((labels[0].share - 0.25).abs() < 0.02 && labels[0].ev == 1.0);
+        assert!((labels[1].share - 0.75).abs() < 0.02 && (labels[1].ev - 4.5).abs() < 0.1);
+
+        // Only the leftmost of several labelled branches counts, and the
+        // same label in different selects is counted as one.
+        let labels = estimate("[1d2 ? \"x\": 1 : \"y\": 2] + [1d2 ? \"x\": 10 : 20]").labels;
+        assert_eq!(labels.len(), 2);
+        assert!((labels[0].share - 0.5).abs() < 0.02 && (labels[0].ev - 16.0).abs() < 0.3);
+
+        // The best of several runs keeps the label of the run it kept.
+        let labels = estimate("[1d20 > 10 ? \"hit\": 1d8 : \"miss\": 0]b2").labels;
+        assert!((labels[0].share - 0.75).abs() < 0.02 && (labels[1].share - 0.25).abs() < 0.02);
+        // A run which takes no labelled branch does not keep the label of
+        // the run before it.
+        let labels = estimate("[1d2 ? \"hit\": 1 : 5]b2").labels;
+        assert!((labels[0].share - 0.25).abs() < 0.02 && labels[0].ev == 1.0);
+        assert!(estimate("[1d2 ? 1 : 2]").labels.is_empty());
+        // Nor does a repeat lose a label taken beside it, unless the run it
+        // kept took one of its own, as the leftmost does.
+        let share = |labels: &[estimator::LabelResults], name| labels.iter().find(|l| l.label == name).unwrap().share;
+        let labels = estimate("[1d2 ? 1 : 2]b2 + [1d2 ? \"x\": 0 : 0]").labels;
+        assert!((share(&labels, "x") - 0.5).abs() < 0.02);
+        let labels = estimate("[1d2 ? \"y\": 1 : 2]b2 + [1d2 ? \"x\": 0 : 0]").labels;
+        assert!((share(&labels, "y") - 0.25).abs() < 0.02 && (share(&labels, "x") - 0.375).abs() < 0.02);
+
+        // The best or worst of several runs keeps the label of the first of
+        // any runs tied for it, and summed or collected runs that of the
+        // last, however they are nested. Each share is held to the exact
+        // chance that the run it should come from took the labelled branch.
+        let kept = "let P = 1d3 in let X = [P ? 1d6 : 1d4] in let Q = 1d3 in let Y = [Q ? 1d6 : 1d4] in";
+        for &(expr, cond) in &[
+            ("[1d3 ? \"a\": 1d6 : 1d4]b2", "[Y > X ? Q : P] == 1"),
+            ("[1d3 ? \"a\": 1d6 : 1d4]w2", "[Y < X ? Q : P] == 1"),
+            ("([1d3 ? \"a\": 1d6 : 1d4]b2)s2", "[Y > X ? Q : P] == 1"),
+            ("([1d3 ? \"a\": 1d6 : 1d4]w2)c2\\h1", "[Y < X ? Q : P] == 1"),
+            ("[1d3 ? \"a\": 1d6 : 1d4]s3", "Q == 1"),
+            ("[1d3 ? \"a\": 1d2 : 1d4]c3\\h1", "Q == 1"),
+        ] {
+            let dist = exact::distribution(&compile(&format!("{} #({})", kept, cond)), 1000).unwrap();
+            let exact = dist.iter().find(|&&(x, _)| x == 1).unwrap().1;
+            assert!((share(&estimate(expr).labels, "a") - exact).abs() < 0.01, "share of a in {}", expr);
+        }
+
+        // Repeating a constant folds it away, but not a label its count takes.
+        for expr in &["(1)b([1d2 ? \"x\": 1 : 2])", "(1)w([1d2 ? \"x\": 1 : 2])", "(2)s([1d2 ? \"y\": 1 : 2])"] {
+            let code = compile(expr);
+            let support = checker::semantic_check(&code).unwrap();
+            let before = estimator::estimate_with(&code, &support, &mut seeded()).labels;
+            let after = estimator::estimate_with(&optimizer::optimize(&code), &support, &mut seeded()).labels;
+            assert_eq!(before.len(), 1, "labels of {}", expr);
+            assert_eq!(before.iter().map(|l| &l.label).collect::<Vec<_>>(), after.iter().map(|l| &l.label).collect::<Vec<_>>());
+            assert!((before[0].share - after[0].share).abs() < 0.02, "share of a label in {}", expr);
+        }
+
+        check_values("[1d3 ? \"a\": 3d6 \"\": 1 : \"a b\": 0]", &[0, 1, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18]);
+        check_optimized("[1 ? \"a\": 3d6 : 1] + 1", &[Num(1), RollConst(3, 6), Label("a".to_string()), Add]);
+    }
+
+    fn estimate_joint(expr: &'static str) -> estimator::JointResults {
+        let code = compile(expr);
+        let supports = checker::joint_check(&code).unwrap();
+        estimator::estimate_joint_with(&optimizer::optimize(&code), &supports, &mut seeded())
+    }
+
+    #[test]
+    fn lets_and_tuples() {
+        use descriptor::SudiceCode::*;
+        // A name stands for the same roll however often it is used, though
+        // the checker bounds each use on its own.
+        check_expr("let A = 1d6 in A + A", 7.0, 3.4157, 11);
+        check_bounds("let A = 1d6 in A - A", -5, 5);
+        check_values("let A = 1 in let A = A + 1 in A", &[2]);
+        assert_eq!((compile("1d6").slots, compile("let A = let B = 1 in B in let C = A in C").slots), (0, 2));
+        check_values("let A = 3d6 in A^1", &[1]);
+        check_type("let HIT = 1d20 > 10 in HIT", SudiceType::Boolean);
+        check_type("let A = 3d6 in (A, 1d6)", SudiceType::Tuple(2));
+        check_type("((1), 2)", SudiceType::Tuple(2));
+        check_type("(1, (2) ** 2, 3)", SudiceType::Tuple(3));
+        check_optimized("let A = 2 + 1 in A * 1d1", &[Num(3), Bind(0, 3), RollConst(1, 1), Load(0), Mul]);
+
+        let mut parser = Rdp::new(StringInput::new("let A = 1 in A + B"));
+        assert!(parser.expr() && parser.end());
+        let e = parser.compile().unwrap_err();
+        assert_eq!((e.span.excerpt("let A = 1 in A + B"), e.message.as_str()), ("B", "Name is not bound by any enclosing let."));
+        check_error("(1, 2) + 3", "(1, 2) + 3", "Attempted to use a tuple as a single value.");
+        check_error("[1d2 ? (1, 2) : 3]", "[1d2 ? (1, 2) : 3]", "Attempted to use a tuple as a single value.");
+        check_error("let A = 1d6 in (A, 1d2)", "(A, 1d2)", "Expected a single value rather than a tuple.");
+
+        let joint = estimate_joint("let R = 1d20 in (#(R == 20), [R == 20 ? 2d6 : 1d6], R)");
+        assert_eq!(joint.values[0], [0, 1]);
+        assert_eq!(joint.total, 2 * 12 * 20 * 2000);
+        assert!((joint.marginal(0).ev - 0.05).abs() < 0.005);
+        assert!((joint.marginal(2).ev - 10.5).abs() < 0.05);
+        assert!((joint.conditional(1, 0, 1).unwrap().ev - 7.0).abs() < 0.1);
+        assert!((joint.conditional(1, 0, 0).unwrap().ev - 3.5).abs() < 0.05);
+        assert_eq!(joint.conditional(0, 2, 20).unwrap().values, [0, 1]);
+        assert_eq!(joint.conditional(0, 2, 20).unwrap().ev, 1.0);
+        assert!(joint.conditional(2, 1, 12).unwrap().values.iter().zip(joint.conditional(2, 1, 12).unwrap().hist)
+            .all(|(&x, n)| x == 20 || n == 0));
+        assert!(joint.outcomes().iter().all(|(v, _)| (v[0] == 1) == (v[2] == 20)));
+        assert!(joint.to_string().contains("X1 = 1:\t"));
+        assert!(!joint.capped && !joint.to_string().contains("[Warning]"));
+        // Combinations of large components are drawn no more than so many
+        // times between them.
+        let joint = estimate_joint("let A = 10d6 in (A, A, A)");
+        assert!(joint.capped && joint.total < 51 * 51 * 51 * 2000);
+        assert!((joint.marginal(2).ev - 35.0).abs() < 0.1);
+        assert!(joint.to_string().contains("[Warning] Observations were capped"));
+
+        let code = compile("let A = 1d2 in (A, A + 1d2)");
+        assert_eq!(exact::distribution(&code, 1000), None);
+    }
+
+    #[test]
+    fn conditioning() {
+        use descriptor::SudiceCode::*;
+        check_expr("let R = 1d20 in R given R > 10", 15.5, 2.8723, 20);
+        check_error("(1d6 given 1d6 > 3) + 1", "1d6 given 1d6 > 3", "Only a whole expression can be given a condition.");
+        check_error("1d6 given 1d6 > 6", "1d6 given 1d6 > 6", "Condition can never hold.");
+        check_optimized("1d6 + 1 given 1 < 2", &[Num(1), Num(1), RollConst(1, 6), Add, Given]);
+
+        // Outcomes the condition rules out keep their rows, with no samples.
+        let results = estimate("let R = 1d20 in R given R > 10");
+        assert_eq!(results.values, (1..=20).collect::<Vec<i64>>());
+        assert!(results.hist[..10].iter().all(|&n| n == 0) && results.hist[10..].iter().all(|&n| n > 0));
+        let dist = exact::distribution(&compile("let R = 1d20 in R given R > 10"), 1000).unwrap();
+        assert_eq!(dist.iter().map(|&(x, _)| x).collect::<Vec<i64>>(), results.values);
+        let results = estimate("let A = 1d4 in A given A == 1");
+        assert!((results.acceptance() - 0.25).abs() < 0.01 && !results.unreliable());
+        assert_eq!((results.total, results.ev), (2000 * 4, 1.0));
+        let results = estimate("let A = 1d200 in A > 100 given A == 1");
+        assert!(results.unreliable() && results.attempts == 2 * 2000 * 100);
+        assert!(results.to_string().contains("[Warning]"));
+
+        let joint = estimate_joint("let R = 1d4 in (R, R + 1d2) given R > 2");
+        assert_eq!(joint.total, 4 * 5 * 2000);
+        assert!((joint.marginal(0).ev - 3.5).abs() < 0.05);
+        assert!((joint.conditional(1, 0, 4).unwrap().ev - 5.5).abs() < 0.05);
+        assert!(joint.conditional(1, 0, 2).is_none());
+    }
+
+    #[test]
+    fn exact_enumeration() {
+        let dist = exact::distribution(&compile("3d6"), 1000).unwrap();
+        assert_eq!(dist.len(), 16);
+        assert_eq!((dist[7].0, (dist[7].1 * 216.0).round()), (10, 27.0));
+        // The number of dice rolled depends on an earlier roll.
+        let dist = exact::distribution(&compile("(1d2)d2"), 1000).unwrap();
+        let dist: Vec<(i64, f64)> = dist.into_iter().map(|(x, p)| (x, p * 8.0)).collect();
+        assert_eq!(dist, vec![(1, 2.0), (2, 3.0), (3, 2.0), (4, 1.0)]);
+        let dist = exact::distribution(&compile("3d6\\l1rl1^5"), 1000).unwrap();
+        let ev: f64 = dist.iter().map(|&(x, p)| x as f64 * p).sum();
+        assert!((ev - 7.8704).abs() < 1e-4, "EV is {}", ev);
+        let (x, p) = exact::distribution(&compile("1d20b2"), 1000).unwrap()[19];
+        assert!(x == 20 && (p - 39.0 / 400.0).abs() < 1e-12);
+        // Runs of a whole expression sum and drop like dice.
+        assert_eq!(exact::distribution(&compile("(1d6)c3\\l1"), 1000), exact::distribution(&compile("3d6\\l1"), 1000));
+        let dist = exact::distribution(&compile("(1d2*2)s2"), 1000).unwrap();
+        let dist: Vec<(i64, f64)> = dist.into_iter().map(|(x, p)| (x, p * 4.0)).collect();
+        assert_eq!(dist, vec![(4, 1.0), (6, 2.0), (8, 1.0)]);
+        assert_eq!(exact::distribution(&compile("2 + 3"), 1), Some(vec![(5, 1.0)]));
+        // A select passes on the dice of its branch, so each is capped.
+        let dist = exact::distribution(&compile("[1d2 ? 2d3 : 2d3]^2"), 1000).unwrap();
+        let dist: Vec<(i64, f64)> = dist.into_iter().map(|(x, p)| (x, (p * 9.0).round())).collect();
+        assert_eq!(dist, vec![(2, 1.0), (3, 4.0), (4, 4.0)]);
+        // A die is rerolled until it lands past the threshold, or until it
+        // has been rolled 101 times, whichever comes first.
+        let dist = exact::distribution(&compile("1d4r<!4"), 1000).unwrap();
+        assert!(dist[3].0 == 4 && (dist[3].1 - (1.0 - 0.75f64.powi(101))).abs() < 1e-12);
+        assert!((dist[0].1 - 0.75f64.powi(101) / 3.0).abs() < 1e-20);
+        let dist = exact::distribution(&compile("2d6rr!1"), 1000).unwrap();
+        assert!(dist[0].0 == 2 && dist[0].1 < 1e-150);
+        assert!(dist[2].0 == 4 && (dist[2].1 - 1.0 / 25.0).abs() < 1e-12);
+        // Each use of a name is the same roll.
+        let (x, p) = exact::distribution(&compile("let A = 1d6 in A - A"), 1000).unwrap()[0];
+        assert!(x == 0 && (p - 1.0).abs() < 1e-12);
+        let dist = exact::distribution(&compile("let A = 1d2 in let B = A + 1d2 in B * A"), 1000).unwrap();
+        let dist: Vec<(i64, f64)> = dist.into_iter().map(|(x, p)| (x, (p * 4.0).round())).collect();
+        assert_eq!(dist, vec![(2, 1.0), (3, 1.0), (6, 1.0), (8, 1.0)]);
+        // A condition renormalizes what is left of the distribution.
+        let dist = exact::distribution(&compile("let A = 1d6 in A + 1d2 given A > 4"), 1000).unwrap();
+        let dist: Vec<(i64, f64)> = dist.into_iter().map(|(x, p)| (x, (p * 4.0).round())).collect();
+        assert_eq!(dist, vec![(2, 0.0), (3, 0.0), (4, 0.0), (5, 0.0), (6, 1.0), (7, 2.0), (8, 1.0)]);
+        // Sets are matched up among the dice of a single roll.
+        let scaled = |expr: &str, by: f64| -> Vec<(i64, f64)> {
+            exact::distribution(&compile(expr), 1000).unwrap().into_iter().map(|(x, p)| (x, (p * by).round())).collect()
+        };
+        let mut highest = vec![(0, 120.0)];
+        highest.extend((1..=6).map(|f| (f, 16.0)));
+        assert_eq!(scaled("highest(3d6, 2)", 216.0), highest);
+        assert_eq!(scaled("longest(3d3)", 27.0), vec![(1, 9.0), (2, 12.0), (3, 6.0)]);
+        assert_eq!(scaled("pairs(4d2)", 16.0), vec![(1, 8.0), (2, 8.0)]);
+        assert_eq!(scaled("largest(3d6)", 36.0), vec![(1, 20.0), (2, 15.0), (3, 1.0)]);
+        let ev: f64 = exact::distribution(&compile("unique(5d6)"), 1000).unwrap().iter().map(|&(x, p)| x as f64 * p).sum();
+        assert!((ev - 6.0 * (1.0 - (5.0f64 / 6.0).powi(5))).abs() < 1e-12);
+        // 4d6 can land in 126 different ways once the dice are sorted.
+        assert_eq!(exact::distribution(&compile("4d6"), 100), None);
+    }
+
+    // Builds a random expression over the grammar, nested at most `depth`
+    // deep, with an operator at the top. Dice are kept small so that the
+    // result can be enumerated. Some are conditioned on a roll which they
+    // share a name with.
+    fn random_expr(rng: &mut StdRng, depth: u32) -> String {
+        let e = random_subexpr(rng, depth, true);
+        if rng.gen_weighted_bool(4) {
+            format!("let W = 1d6 in ({}) + W given W {} {}", e, rng.choose(&["<", ">", "!="]).unwrap(), rng.gen_range(2, 6))
+        } else {
+            e
+        }
+    }
+
+    fn random_subexpr(rng: &mut StdRng, depth: u32, top: bool) -> String {
+        if depth == 0 || (!top && rng.gen_weighted_bool(3)) {
+            return if rng.gen() {
+                rng.gen_range(0, 7).to_string()
+            } else {
+                format!("{}d{}", rng.gen_range(1, 4), rng.gen_range(1, 7))
+            };
+        }
+        let a = random_subexpr(rng, depth - 1, false);
+        let b = random_subexpr(rng, depth - 1, false);
+        match rng.gen_range(0, 15) {
+            0 => format!("({})d({})", a, b),
+            1 => {
+                let n = rng.gen_range(2, 5);
+                let op = rng.choose(&["\\l", "\\h", "rl", "rh"]).unwrap();
+                format!("{}d({}){}({})", n, b, op, rng.gen_range(1, n))
+            },
+            2 => format!("({}d{}){}({})", rng.gen_range(1, 4), rng.gen_range(1, 7), rng.choose(&["^", "_", "rr", "r<", "r>", "rr!", "r<!", "r>!"]).unwrap(), a),
+            3 => format!("({}){}{}", a, rng.choose(&["b", "w", "s"]).unwrap(), rng.gen_range(1, 4)),
+            4 => {
+                let c = random_subexpr(rng, depth - 1, false);
+                format!("[({}) {} ({}) ? ({}) : ({})]", a, rng.choose(&["<", ">", "==", "!="]).unwrap(), b, c, a)
+            },
+            5 => {
+                let c = random_subexpr(rng, depth - 1, false);
+                format!("[({}) ? \"a\": ({}) ({}) : \"b\": ({})]", a, b, c, rng.gen_range(0, 7))
+            },
+            6 => format!("|{}| {} -({})", a, rng.choose(&["+", "-"]).unwrap(), b),
+            7 => format!("{}(({}) < 4 {} not ({}) > 2)", rng.choose(&["#", ""]).unwrap(), a, rng.choose(&["and", "or", "xor"]).unwrap(), b),
+            8 => format!("{}({}, {})", rng.choose(&["min", "max"]).unwrap(), a, b),
+            9 => format!("({}) ** {}", a, rng.gen_range(0, 4)),
+            10 => format!("(let V = ({}) in V {} ({}) {} V)", a, rng.choose(&["+", "-", "*"]).unwrap(), b, rng.choose(&["<", ">", "=="]).unwrap()),
+            11 => {
+                let n = rng.gen_range(2, 4);
+                format!("(({})c{}){}({})", a, n, rng.choose(&["\\l", "\\h", "^", "_"]).unwrap(), rng.gen_range(1, n))
+            },
+            12 => {
+                let pool = if rng.gen() {
+                    format!("{}d{}", rng.gen_range(1, 5), rng.gen_range(1, 7))
+                } else {
+                    format!("({})c{}", a, rng.gen_range(1, 4))
+                };
+                match rng.gen_range(0, 5) {
+                    0 => format!("highest({}, {})", pool, rng.gen_range(1, 4)),
+                    f => format!("{}({})", ["largest", "pairs", "longest", "unique"][f - 1], pool),
+                }
+            },
+            _ => format!("({}) {} ({})", a, rng.choose(&["+", "-", "*", "/", "/^", "/~", "%"]).unwrap(), b),
+        }
+    }
+
+    // Asserts that sampled results are consistent with the exact
+    // distribution, both by a chi-squared test, pooling outcomes until each
+    // bin expects at least 5 observations, and by a Kolmogorov-Smirnov test.
+    // Both thresholds correspond to a false positive rate around 1e-6.
+    fn assert_fits(expr: &str, exact: &[(i64, f64)], results: &SudiceResults) {
+        let n = results.total as f64;
+        let observed = |x: i64| results.values.iter().position(|&v| v == x).map_or(0, |i| results.hist[i]) as f64;
+        for (i, &x) in results.values.iter().enumerate() {
+            assert!(results.hist[i] == 0 || exact.iter().any(|&(v, p)| v == x && p > 0.0), "{} sampled impossible outcome {}", expr, x);
+        }
+        // Outcomes a condition rules out have nothing to compare.
+        let exact: Vec<(i64, f64)> = exact.iter().cloned().filter(|&(_, p)| p > 0.0).collect();
+
+        let (mut chi, mut bins) = (0.0, 0);
+        let (mut o, mut e) = (0.0, 0.0);
+        let (mut cum_o, mut cum_e, mut ks) = (0.0, 0.0, 0.0f64);
+        for (i, &(x, p)) in exact.iter().enumerate() {
+            o += observed(x);
+            e += p * n;
+            if e >= 5.0 || i == exact.len() - 1 {
+                chi += (o - e) * (o - e) / e;
+                bins += 1;
+                o = 0.0;
+                e = 0.0;
             }
+            cum_o += observed(x) / n;
+            cum_e += p;
+            ks = ks.max((cum_o - cum_e).abs());
+        }
+        if bins > 1 {
+            // Wilson-Hilferty: the cube root of chi-squared over its degrees
+            // of freedom is close to normally distributed.
+            let k = (bins - 1) as f64;
+            let z = ((chi / k).powf(1.0 / 3.0) - (1.0 - 2.0 / (9.0 * k))) / (2.0 / (9.0 * k)).sqrt();
+            assert!(z < 5.0, "{} fails chi-squared with {} over {} bins", expr, chi, bins);
+        }
+        assert!(ks * n.sqrt() < 2.6, "{} fails Kolmogorov-Smirnov with D = {}", expr, ks);
+    }
+
+    #[test]
+    fn matches_exact_distribution() {
+        let mut rng = seeded();
+        let mut tested = 0;
+        for _ in 0..5000 {
+            let expr = random_expr(&mut rng, 3);
+            let code = compile(&expr);
+            let support = match checker::semantic_check(&code) {
+                Ok(support) if support.len() <= 40 => support,
+                _ => continue,
+            };
+            let exact = match exact::distribution(&code, 5000) {
+                Some(exact) => exact,
+                None => continue,
+            };
+            let total: f64 = exact.iter().map(|&(_, p)| p).sum();
+            assert!((total - 1.0).abs() < 1e-9, "{} has total probability {}", expr, total);
+            for &(x, _) in &exact {
+                assert!(support.contains(x), "{} can produce {}, which the checker found unreachable", expr, x);
+            }
+
+            let optimized = optimizer::optimize(&code);
+            let after = exact::distribution(&optimized, 5000).unwrap();
+            assert_eq!(exact.len(), after.len(), "optimizing {} changed its outcomes", expr);
+            for (&(x, p), &(y, q)) in exact.iter().zip(after.iter()) {
+                assert!(x == y && (p - q).abs() < 1e-9, "optimizing {} changed P({}) from {} to {}", expr, x, p, q);
+            }
+
+            let results = estimator::estimate_with(&optimized, &support, &mut rng);
+            assert_fits(&expr, &exact, &results);
+            tested += 1;
+            if tested == 80 {
+                return;
+            }
+        }
+        panic!("Only {} generated expressions could be tested.", tested);
+    }
+
+    #[test]
+    fn fuzzing() {
+        let mut rng = seeded();
+        for input in fuzz::CORPUS {
+            fuzz::run_one(&mut rng, input);
         }
+        assert_eq!(fuzz::fuzz(0, 3000), None);
     }
 }
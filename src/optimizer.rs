@@ -0,0 +1,283 @@
+use descriptor::{self, from_bool, SudiceCode, SudiceExpression, SudiceSpan};
+
+use std::cmp;
+use std::vec::Vec;
+
+// An expression tree recovered from the bytecode. Binary operators keep
+// their left operand (the top of stack) first, and a select keeps its
+// predicate first followed by each of its branches in order. A let keeps
+// its bound value and then its body, and a tuple its elements in order.
+struct Node {
+    code: SudiceCode,
+    span: SudiceSpan,
+    args: Vec<Node>,
+}
+
+impl Node {
+    fn leaf(code: SudiceCode, span: SudiceSpan) -> Node {
+        Node { code, span, args: Vec::new() }
+    }
+
+    fn constant(&self) -> Option<i64> {
+        match self.code {
+            SudiceCode::Num(i) => Some(i),
+            SudiceCode::Bool(b) => Some(from_bool(b)),
+            _ => None,
+        }
+    }
+
+    // Whether the interpreter may leave a vector of dice behind for this
+    // node rather than a sum. Selects and repeats pass their operands
+    // through as they are.
+    fn may_be_vector(&self) -> bool {
+        match self.code {
+            SudiceCode::Roll | SudiceCode::RollConst(..) |
+            SudiceCode::Reroll | SudiceCode::RerollLowest | SudiceCode::RerollHighest |
+            SudiceCode::RerollBelow | SudiceCode::RerollAbove | SudiceCode::RerollUntil |
+            SudiceCode::RerollBelowUntil | SudiceCode::RerollAboveUntil |
+            SudiceCode::DropLowest | SudiceCode::DropHighest | SudiceCode::CollectOf(_) => true,
+            SudiceCode::Ceil | SudiceCode::Floor | SudiceCode::Label(_) |
+            SudiceCode::BestOf(_) | SudiceCode::WorstOf(_) => self.args[0].may_be_vector(),
+            SudiceCode::Select(_) => self.args[1..].iter().any(Node::may_be_vector),
+            SudiceCode::Bind(..) => self.args[1].may_be_vector(),
+            _ => false,
+        }
+    }
+
+    // Whether a label is taken anywhere beneath this node, so that it must
+    // be run even when its value is known.
+    fn has_label(&self) -> bool {
+        matches!(self.code, SudiceCode::Label(_)) || self.args.iter().any(Node::has_label)
+    }
+
+    // Whether this node works on the individual dice of its top of stack
+    // operand, rather than summing it like everything else.
+    fn keeps_dice(&self) -> bool {
+        matches!(self.code,
+            SudiceCode::Reroll | SudiceCode::RerollLowest | SudiceCode::RerollHighest |
+            SudiceCode::RerollBelow | SudiceCode::RerollAbove | SudiceCode::RerollUntil |
+            SudiceCode::RerollBelowUntil | SudiceCode::RerollAboveUntil |
+            SudiceCode::DropLowest | SudiceCode::DropHighest |
+            SudiceCode::Ceil | SudiceCode::Floor |
+            SudiceCode::Largest | SudiceCode::Pairs | SudiceCode::Longest | SudiceCode::Unique | SudiceCode::Highest)
+    }
+}
+
+// Rewrites an expression into equivalent bytecode which is cheaper to
+// interpret: constant subexpressions are folded, double negations are
+// removed, rolls of a constant number of constant-sized dice are fused,
+// and selects on a constant predicate are replaced by the chosen branch.
+//
+// Only expressions which have passed the semantic check should be run
+// through here, but anything which would fail at runtime, such as dividing
+// by zero, is left in place rather than folded.
+pub fn optimize(d: &SudiceExpression) -> SudiceExpression {
+    let root = build(d, 0, d.code.len(), false);
+    let mut out = Vec::with_capacity(d.code.len());
+    emit(simplify(root, true), &mut out);
+    let (code, spans) = out.into_iter().unzip();
+    SudiceExpression { source: d.source.clone(), code, spans, slots: d.slots }
+}
+
+// Rebuilds the tree for the bytecode from `start` until `end`, or, for a
+// select branch, until that branch's jump.
+fn build(d: &SudiceExpression, start: usize, end: usize, until_jump: bool) -> Node {
+    let mut stack: Vec<Node> = Vec::new();
+    let mut dcp = start;
+    while dcp < end {
+        let span = d.spans[dcp];
+        match d.code[dcp] {
+            SudiceCode::Num(_) | SudiceCode::Bool(_) | SudiceCode::RollConst(..) |
+            SudiceCode::Load(_) => stack.push(Node::leaf(d.code[dcp].clone(), span)),
+            SudiceCode::Not | SudiceCode::Count | SudiceCode::Abs | SudiceCode::Neg | SudiceCode::Label(_) |
+            SudiceCode::Largest | SudiceCode::Pairs | SudiceCode::Longest | SudiceCode::Unique => {
+                let x = stack.pop().unwrap();
+                stack.push(Node { code: d.code[dcp].clone(), span, args: vec![x] });
+            },
+            SudiceCode::Select(ref offsets) => {
+                let mut args = vec![stack.pop().unwrap()];
+                let mut branch = dcp + 1;
+                for offset in offsets {
+                    args.push(build(d, branch, d.code.len(), true));
+                    branch = dcp + offset + 1;
+                }
+                stack.push(Node { code: d.code[dcp].clone(), span, args });
+                dcp += offsets[offsets.len()-1];
+            },
+            SudiceCode::Bind(_, len) => {
+                let bound = stack.pop().unwrap();
+                let body = build(d, dcp + 1, dcp + len + 1, false);
+                stack.push(Node { code: d.code[dcp].clone(), span, args: vec![bound, body] });
+                dcp += len;
+            },
+            SudiceCode::Tuple(n) => {
+                let args = (0..n).map(|_| stack.pop().unwrap()).collect();
+                stack.push(Node { code: d.code[dcp].clone(), span, args });
+            },
+            SudiceCode::Jump(_) => if until_jump {
+                break;
+            } else {
+                panic!("Error: Illegal bytecode sequence: Should not reach jump!");
+            },
+            ref code => {
+                let left = stack.pop().unwrap();
+                let right = stack.pop().unwrap();
+                stack.push(Node { code: code.clone(), span, args: vec![left, right] });
+            },
+        }
+        dcp += 1;
+    }
+    stack.pop().unwrap()
+}
+
+fn emit(node: Node, out: &mut Vec<(SudiceCode, SudiceSpan)>) {
+    let Node { code, span, args } = node;
+    let mut args = args.into_iter();
+    match code {
+        SudiceCode::Num(_) | SudiceCode::Bool(_) | SudiceCode::RollConst(..) | SudiceCode::Load(_) => {},
+        SudiceCode::Not | SudiceCode::Count | SudiceCode::Abs | SudiceCode::Neg | SudiceCode::Label(_) |
+        SudiceCode::Largest | SudiceCode::Pairs | SudiceCode::Longest | SudiceCode::Unique => emit(args.next().unwrap(), out),
+        SudiceCode::Select(_) => {
+            emit(args.next().unwrap(), out);
+            let at = out.len();
+            out.push((SudiceCode::Select(Vec::new()), span));
+            let mut starts = Vec::new();
+            let mut jumps = Vec::new();
+            for branch in args {
+                let branch_span = branch.span;
+                starts.push(out.len());
+                emit(branch, out);
+                jumps.push(out.len());
+                out.push((SudiceCode::Jump(0), branch_span));
+            }
+            let end = out.len();
+            for j in jumps {
+                out[j].0 = SudiceCode::Jump(end - j - 1);
+            }
+            let mut offsets: Vec<usize> = starts[1..].iter().map(|s| s - at - 1).collect();
+            offsets.push(end - at - 1);
+            out[at].0 = SudiceCode::Select(offsets);
+            return;
+        },
+        SudiceCode::Bind(slot, _) => {
+            emit(args.next().unwrap(), out);
+            let at = out.len();
+            out.push((code, span));
+            emit(args.next().unwrap(), out);
+            out[at].0 = SudiceCode::Bind(slot, out.len() - at - 1);
+            return;
+        },
+        SudiceCode::Tuple(_) => for arg in args.rev() {
+            emit(arg, out);
+        },
+        SudiceCode::BestOf(_) | SudiceCode::WorstOf(_) | SudiceCode::SumOf(_) | SudiceCode::CollectOf(_) => {
+            let left = args.next().unwrap();
+            emit(args.next().unwrap(), out);
+            let start = out.len();
+            emit(left, out);
+            let offset = out.len() - start;
+            out.push((match code {
+                SudiceCode::BestOf(_) => SudiceCode::BestOf(offset),
+                SudiceCode::WorstOf(_) => SudiceCode::WorstOf(offset),
+                SudiceCode::SumOf(_) => SudiceCode::SumOf(offset),
+                _ => SudiceCode::CollectOf(offset),
+            }, span));
+            return;
+        },
+        _ => {
+            let left = args.next().unwrap();
+            emit(args.next().unwrap(), out);
+            emit(left, out);
+        },
+    }
+    out.push((code, span));
+}
+
+// Evaluates an operator on constant operands exactly as the interpreter
+// would, giving up wherever the interpreter would saturate or panic.
+fn fold(code: &SudiceCode, x: i64, y: Option<i64>) -> Option<i64> {
+    match (code, y) {
+        (&SudiceCode::Not, None) => Some(from_bool(x != 1)),
+        (&SudiceCode::Count, None) => Some((x == 1) as i64),
+        (&SudiceCode::Abs, None) => x.checked_abs(),
+        (&SudiceCode::Neg, None) => x.checked_neg(),
+        (&SudiceCode::Add, Some(y)) => x.checked_add(y),
+        (&SudiceCode::Sub, Some(y)) => x.checked_sub(y),
+        (&SudiceCode::Mul, Some(y)) => x.checked_mul(y),
+        (&SudiceCode::Div, Some(y)) => descriptor::checked_div_floor(x, y),
+        (&SudiceCode::DivCeil, Some(y)) => descriptor::checked_div_ceil(x, y),
+        (&SudiceCode::DivRound, Some(y)) => descriptor::checked_div_round(x, y),
+        (&SudiceCode::Mod, Some(y)) => descriptor::checked_rem_floor(x, y),
+        (&SudiceCode::Pow, Some(y)) => descriptor::checked_pow(x, y),
+        (&SudiceCode::Min, Some(y)) => Some(cmp::min(x, y)),
+        (&SudiceCode::Max, Some(y)) => Some(cmp::max(x, y)),
+        (&SudiceCode::SumOf(_), Some(y)) => x.checked_mul(y),
+        (&SudiceCode::Ceil, Some(y)) => Some(cmp::min(x, y)),
+        (&SudiceCode::Floor, Some(y)) => Some(cmp::max(x, y)),
+        (&SudiceCode::Lt, Some(y)) => Some(from_bool(x < y)),
+        (&SudiceCode::Gt, Some(y)) => Some(from_bool(x > y)),
+        (&SudiceCode::Eq, Some(y)) => Some(from_bool(x == y)),
+        (&SudiceCode::Ne, Some(y)) => Some(from_bool(x != y)),
+        (&SudiceCode::And, Some(y)) => Some(from_bool(x == 1 && y == 1)),
+        (&SudiceCode::Or, Some(y)) => Some(from_bool(x == 1 || y == 1)),
+        (&SudiceCode::Xor, Some(y)) => Some(from_bool((x == 1) != (y == 1))),
+        _ => None,
+    }
+}
+
+// Simplifies a node and everything beneath it. Negations sum their
+// operand, so replacing a pair with an operand which may be a vector of
+// dice is only safe when `summed`, meaning something above will sum it
+// anyway. Selects and repeats pass their operands through, so those
+// inherit `summed` from the node itself. Labels pass their operand through
+// too, summed or not, and are never folded away, as do lets their bodies.
+fn simplify(node: Node, summed: bool) -> Node {
+    let keeps_dice = node.keeps_dice();
+    let Node { code, span, args } = node;
+    let passed = |i: usize| match code {
+        SudiceCode::Label(_) => true,
+        SudiceCode::Select(_) => i > 0,
+        SudiceCode::BestOf(_) | SudiceCode::WorstOf(_) => i == 0,
+        SudiceCode::Bind(..) => i == 1,
+        _ => false,
+    };
+    let mut args: Vec<Node> = args.into_iter().enumerate()
+        .map(|(i, arg)| simplify(arg, if passed(i) { summed } else { i > 0 || !keeps_dice }))
+        .collect();
+    let x = args.first().and_then(Node::constant);
+    let y = args.get(1).and_then(Node::constant);
+    match code {
+        SudiceCode::Roll => if let (Some(n), Some(size)) = (x, y) {
+            if n >= 0 && size > 0 {
+                return Node::leaf(SudiceCode::RollConst(n, size), span);
+            }
+        },
+        // However many times a constant is repeated, it stays the same,
+        // though a count which takes a label is still run for it.
+        SudiceCode::BestOf(_) | SudiceCode::WorstOf(_) => if let Some(x) = x {
+            if !args[1].has_label() {
+                return Node::leaf(SudiceCode::Num(x), span);
+            }
+        },
+        // A predicate of 1 selects the first branch, 2 through len-1 select
+        // the rest in order, and anything else the default.
+        SudiceCode::Select(_) => if let Some(t) = x {
+            let len = args.len() - 1;
+            let branch = if t >= 1 && t < len as i64 { t as usize } else { len };
+            return args.swap_remove(branch);
+        },
+        SudiceCode::Neg if args[0].code == SudiceCode::Neg && (summed || !args[0].args[0].may_be_vector()) => {
+            return args.pop().unwrap().args.pop().unwrap();
+        },
+        _ => {},
+    }
+    let folded = match args.len() {
+        1 => x.and_then(|x| fold(&code, x, None)),
+        2 => x.and_then(|x| y.and_then(|y| fold(&code, x, Some(y)))),
+        _ => None,
+    };
+    match folded {
+        Some(i) => Node::leaf(SudiceCode::Num(i), span),
+        None => Node { code, span, args },
+    }
+}
@@ -1,38 +1,85 @@
-use descriptor::{SudiceCode, SudiceExpression};
+use descriptor::{SudiceCode, SudiceError, SudiceExpression, SudiceSpan};
 
 use pest::prelude::*;
 
+use std::cmp;
 use std::collections::LinkedList;
-use std::iter::FromIterator;
+
+type SudiceCodeList = LinkedList<(SudiceCode, SudiceSpan)>;
+
+// The slot of a name no enclosing let has bound yet. Every let replaces it
+// in the names it binds as it is compiled, so any left over are errors.
+const UNBOUND: usize = usize::MAX;
 
 impl_rdp! {
     grammar! {
-        expr = _{
-            { ["("] ~ expr ~ [")"] | select | abs | neg | num }
-            bnry = { and | or }
-            cond = { lt | gt | eq | ne }
-            sum  = { plus  | minus }
-            prod = { times | slash }
-            dice = { roll | reroll | rerolll | rerollh | dropl | droph | ceil | floor | best | worst }
+        // Each level of precedence climbs on its own, as pest's climber
+        // never returns when the operand of an operator binding tighter than
+        // the one before it is missing, as in `1 + 2d`.
+        expr      = _{ { logical } cndn = { given } }
+        logical   = _{ { comparand } bnry = { and | or | xor } }
+        comparand = _{ { addend } cond = { lt | gt | eq | ne } }
+        addend    = _{ { factor } sum  = { plus  | minus } }
+        factor    = _{ { power } prod = { times | slashc | slashr | slash | percent } }
+        // Exponents are right-associative, so `2 ** 3 ** 2` is `2 ** 9`,
+        // but the climber never returns from a right-associative operator
+        // missing its right operand, as in `2 **`, so they climb left to
+        // right and are regrouped once parsed.
+        power     = _{ { operand } raise = { pow } }
+        operand   = _{
+            { primary }
+            dice = { roll | rerollu | reroll | rerolll | rerollh | rerollbu | rerollb | rerollau | rerolla | dropl | droph | ceil | floor | best | worst | sumof | collect }
         }
+        primary = _{ paren | select | abs | neg | not | count | truth | min | max | largest | highest | pairs | longest | unique | bind | name | num }
+        // Holds a tuple when it has more than one element.
+        paren  = { ["("] ~ elements ~ [")"] }
+        elements = _{ { expr } tuple = { comma } }
         abs    = { ["|"] ~ expr ~ ["|"] }
         neg    = { ["-"] ~ expr }
-        select = { selbegin ~ expr ~ qmark ~ expr+ ~ ecase ~ expr ~ selend }
+        not    = { ["not"] ~ expr }
+        // Binds as tightly as it can, so that counts of several conditions
+        // can be added up as in `#(1d20 > 8) + #(1d20 > 8)`.
+        count  = { ["#"] ~ primary }
+        truth  = { ["true"] | ["false"] }
+        min    = { ["min"] ~ ["("] ~ expr ~ [","] ~ expr ~ [")"] }
+        max    = { ["max"] ~ ["("] ~ expr ~ [","] ~ expr ~ [")"] }
+        // None of these starts with the letter of a dice operator, which a
+        // select branch before it could otherwise be taken to end with.
+        largest = { ["largest"] ~ ["("] ~ expr ~ [")"] }
+        highest = { ["highest"] ~ ["("] ~ expr ~ [","] ~ expr ~ [")"] }
+        pairs   = { ["pairs"] ~ ["("] ~ expr ~ [")"] }
+        longest = { ["longest"] ~ ["("] ~ expr ~ [")"] }
+        unique  = { ["unique"] ~ ["("] ~ expr ~ [")"] }
+        // The body takes in everything after it, like a negation does.
+        bind   = { ["let"] ~ name ~ ["="] ~ expr ~ ["in"] ~ expr }
+        select = { selbegin ~ expr ~ qmark ~ (labelled | expr)+ ~ ecase ~ (labelled | expr) ~ selend }
+        labelled = { label ~ [":"] ~ expr }
 
         plus     = { ["+"] }
         minus    = { ["-"] }
         times    = { ["*"] }
         slash    = { ["/"] }
+        slashc   = { ["/^"] }
+        slashr   = { ["/~"] }
+        percent  = { ["%"] }
+        pow      = { ["**"] }
         roll     = { ["d"] }
         reroll   = { ["rr"] }
         rerolll  = { ["rl"] }
         rerollh  = { ["rh"] }
+        rerollb  = { ["r<"] }
+        rerolla  = { ["r>"] }
+        rerollu  = { ["rr!"] }
+        rerollbu = { ["r<!"] }
+        rerollau = { ["r>!"] }
         dropl    = { ["\\l"] }
         droph    = { ["\\h"] }
         ceil     = { ["^"] }
         floor    = { ["_"] }
         best     = { ["b"] }
         worst    = { ["w"] }
+        sumof    = { ["s"] }
+        collect  = { ["c"] }
         qmark    = { ["?"] }
         ecase    = { [":"] }
         selbegin = { ["["] }
@@ -43,111 +90,288 @@ impl_rdp! {
         ne       = { ["!="] }
         and      = { ["and"] }
         or       = { ["or"] }
+        xor      = { ["xor"] }
+        given    = { ["given"] }
+        comma    = { [","] }
 
+        // Names are written in capitals, so that none can be mistaken for an
+        // operator, as `d` or `b` could be.
+        name       = @{ ['A'..'Z'] ~ (['A'..'Z'] | ['0'..'9'] | ["_"])* }
+        label      = @{ ["\""] ~ (!["\""] ~ any)* ~ ["\""] }
         num        = @{ ["-"]? ~ (["0"] | ['1'..'9'] ~ ['0'..'9']*) }
         whitespace = _{ [" "] }
     }
 
     process! {
-        compile(&self) -> SudiceExpression {
+        compile(&self) -> Result<SudiceExpression, SudiceError> {
             (expr: _expr()) => {
-                let code = Vec::from_iter(expr.into_iter());
-                SudiceExpression {
-                    code: code,
+                let source = self.input().slice(0, self.input().len()).to_string();
+                // Literals which do not fit were compiled as placeholders.
+                for &(ref code, span) in expr.iter() {
+                    match *code {
+                        SudiceCode::Num(_) if span.excerpt(&source).parse::<i64>().is_err() => {
+                            return Err(SudiceError::new(span, "Number does not fit in a 64-bit integer."));
+                        },
+                        SudiceCode::Load(UNBOUND) => {
+                            return Err(SudiceError::new(span, "Name is not bound by any enclosing let."));
+                        },
+                        _ => {},
+                    }
                 }
+                let slots = expr.iter().filter_map(|e| match e.0 {
+                    SudiceCode::Bind(slot, _) => Some(slot + 1),
+                    _ => None,
+                }).max().unwrap_or(0);
+                let (code, spans) = expr.into_iter().unzip();
+                Ok(SudiceExpression {
+                    source,
+                    code,
+                    spans,
+                    slots,
+                })
             }
         }
-        _expr(&self) -> LinkedList<SudiceCode> {
-            (&num: num) => {
+        _expr(&self) -> SudiceCodeList {
+            (token: num) => {
+                let num = self.input().slice(token.start, token.end);
+                let mut dl = LinkedList::new();
+                dl.push_front((SudiceCode::Num(num.parse::<i64>().unwrap_or(0)), span(&token)));
+                dl
+            },
+            (token: name) => {
+                let mut dl = LinkedList::new();
+                dl.push_front((SudiceCode::Load(UNBOUND), span(&token)));
+                dl
+            },
+            (token: truth) => {
+                let value = self.input().slice(token.start, token.end) == "true";
                 let mut dl = LinkedList::new();
-                dl.push_front(SudiceCode::Num(num.parse::<i64>().unwrap()));
+                dl.push_front((SudiceCode::Bool(value), span(&token)));
                 dl
             },
-            (_: bnry, mut left: _expr(), op, mut right: _expr()) => {
+            (whole: cndn, mut left: _expr(), _: given, mut right: _expr()) => {
+                let span = join(&whole, &right);
+                right.append(&mut left);
+                right.push_back((SudiceCode::Given, span));
+                right
+            },
+            (whole: bnry, mut left: _expr(), op, mut right: _expr()) => {
+                let span = join(&whole, &right);
                 right.append(&mut left);
-                right.push_back(match op.rule {
+                right.push_back((match op.rule {
                     Rule::and  => SudiceCode::And,
                     Rule::or => SudiceCode::Or,
+                    Rule::xor => SudiceCode::Xor,
                     _ => unreachable!()
-                });
+                }, span));
                 right
             },
-            (_: cond, mut left: _expr(), sign, mut right: _expr()) => {
+            (whole: cond, mut left: _expr(), sign, mut right: _expr()) => {
+                let span = join(&whole, &right);
                 right.append(&mut left);
-                right.push_back(match sign.rule {
+                right.push_back((match sign.rule {
                     Rule::lt => SudiceCode::Lt,
                     Rule::gt => SudiceCode::Gt,
                     Rule::eq => SudiceCode::Eq,
                     Rule::ne => SudiceCode::Ne,
                     _ => unreachable!()
-                });
+                }, span));
                 right
             },
-            (_: sum, mut left: _expr(), sign, mut right: _expr()) => {
+            (whole: sum, mut left: _expr(), sign, mut right: _expr()) => {
+                let span = join(&whole, &right);
                 right.append(&mut left);
-                right.push_back(match sign.rule {
+                right.push_back((match sign.rule {
                     Rule::plus  => SudiceCode::Add,
                     Rule::minus => SudiceCode::Sub,
                     _ => unreachable!()
-                });
+                }, span));
                 right
             },
-            (_: prod, mut left: _expr(), sign, mut right: _expr()) => {
+            (whole: prod, mut left: _expr(), sign, mut right: _expr()) => {
+                let span = join(&whole, &right);
                 right.append(&mut left);
-                right.push_back(match sign.rule {
+                right.push_back((match sign.rule {
                     Rule::times => SudiceCode::Mul,
                     Rule::slash => SudiceCode::Div,
+                    Rule::slashc => SudiceCode::DivCeil,
+                    Rule::slashr => SudiceCode::DivRound,
+                    Rule::percent => SudiceCode::Mod,
                     _ => unreachable!()
-                });
+                }, span));
                 right
             },
-            (_: dice, mut left: _expr(), cmd, mut right: _expr()) => {
+            (whole: raise, mut bases: _bases(), op: pow, mut power: _expr()) => {
+                // Every power in the chain runs to its end, each starting
+                // where its base does, which is past the `**` before it.
+                let end = join(&whole, &power).end;
+                bases.1.push(op.end);
+                while let Some(mut base) = bases.0.pop() {
+                    bases.1.pop();
+                    let start = bases.1.last().map_or(whole.start, |&after| {
+                        let gap = self.input().slice(after, end);
+                        after + gap.len() - gap.trim_start().len()
+                    });
+                    power.append(&mut base);
+                    power.push_back((SudiceCode::Pow, SudiceSpan::new(start, end)));
+                }
+                power
+            },
+            (whole: dice, mut left: _expr(), cmd, mut right: _expr()) => {
                 let offset = left.len();
+                let span = join(&whole, &right);
                 right.append(&mut left);
-                right.push_back(match cmd.rule {
+                right.push_back((match cmd.rule {
                     Rule::roll    => SudiceCode::Roll,
                     Rule::reroll  => SudiceCode::Reroll,
                     Rule::rerolll => SudiceCode::RerollLowest,
                     Rule::rerollh => SudiceCode::RerollHighest,
+                    Rule::rerollb => SudiceCode::RerollBelow,
+                    Rule::rerolla => SudiceCode::RerollAbove,
+                    Rule::rerollu  => SudiceCode::RerollUntil,
+                    Rule::rerollbu => SudiceCode::RerollBelowUntil,
+                    Rule::rerollau => SudiceCode::RerollAboveUntil,
                     Rule::dropl   => SudiceCode::DropLowest,
                     Rule::droph   => SudiceCode::DropHighest,
                     Rule::ceil    => SudiceCode::Ceil,
                     Rule::floor   => SudiceCode::Floor,
                     Rule::best    => SudiceCode::BestOf(offset),
                     Rule::worst   => SudiceCode::WorstOf(offset),
+                    Rule::sumof   => SudiceCode::SumOf(offset),
+                    Rule::collect => SudiceCode::CollectOf(offset),
                     _ => unreachable!()
-                });
+                }, span));
                 right
             },
-            (_: abs, mut e: _expr()) => {
-                e.push_back(SudiceCode::Abs);
+            (whole: paren, elements: _elements()) => {
+                // Elements are laid out last first, as operands are, so that
+                // the first ends up on top.
+                let n = elements.len();
+                let mut tuple = LinkedList::new();
+                for mut e in elements.into_iter().rev() {
+                    tuple.append(&mut e);
+                }
+                if n > 1 {
+                    tuple.push_back((SudiceCode::Tuple(n), span(&whole)));
+                }
+                tuple
+            },
+            (whole: bind, &name: name, mut bound: _expr(), mut body: _expr()) => {
+                // A let takes the first slot, pushing every let in its body
+                // one slot along, so that nested lets never share a slot.
+                // Lets in the bound value are done with their slots before
+                // this one is filled, so they are left as they are.
+                for &mut (ref mut code, span) in body.iter_mut() {
+                    match *code {
+                        SudiceCode::Bind(ref mut slot, _) => *slot += 1,
+                        SudiceCode::Load(ref mut slot) => if *slot != UNBOUND {
+                            *slot += 1;
+                        } else if self.input().slice(span.start, span.end) == name {
+                            *slot = 0;
+                        },
+                        _ => {},
+                    }
+                }
+                bound.push_back((SudiceCode::Bind(0, body.len()), span(&whole)));
+                bound.append(&mut body);
+                bound
+            },
+            (whole: abs, mut e: _expr()) => {
+                e.push_back((SudiceCode::Abs, span(&whole)));
                 e
             },
-            (_: neg, mut e: _expr()) => {
-                e.push_back(SudiceCode::Neg);
+            (whole: neg, mut e: _expr()) => {
+                e.push_back((SudiceCode::Neg, span(&whole)));
                 e
             },
-            (_: select, _: selbegin, mut pred: _expr(), _: qmark, mut rest: _jump_seq()) => {
+            (whole: min, mut left: _expr(), mut right: _expr()) => {
+                right.append(&mut left);
+                right.push_back((SudiceCode::Min, span(&whole)));
+                right
+            },
+            (whole: max, mut left: _expr(), mut right: _expr()) => {
+                right.append(&mut left);
+                right.push_back((SudiceCode::Max, span(&whole)));
+                right
+            },
+            (whole: highest, mut pool: _expr(), mut k: _expr()) => {
+                k.append(&mut pool);
+                k.push_back((SudiceCode::Highest, span(&whole)));
+                k
+            },
+            (whole: largest, mut e: _expr()) => {
+                e.push_back((SudiceCode::Largest, span(&whole)));
+                e
+            },
+            (whole: pairs, mut e: _expr()) => {
+                e.push_back((SudiceCode::Pairs, span(&whole)));
+                e
+            },
+            (whole: longest, mut e: _expr()) => {
+                e.push_back((SudiceCode::Longest, span(&whole)));
+                e
+            },
+            (whole: unique, mut e: _expr()) => {
+                e.push_back((SudiceCode::Unique, span(&whole)));
+                e
+            },
+            (whole: labelled, &name: label, mut e: _expr()) => {
+                let name = name[1..name.len()-1].to_string();
+                e.push_back((SudiceCode::Label(name), span(&whole)));
+                e
+            },
+            (whole: not, mut e: _expr()) => {
+                e.push_back((SudiceCode::Not, span(&whole)));
+                e
+            },
+            (whole: count, mut e: _expr()) => {
+                e.push_back((SudiceCode::Count, span(&whole)));
+                e
+            },
+            (whole: select, _: selbegin, mut pred: _expr(), _: qmark, mut rest: _jump_seq()) => {
                 let mut sum: usize = 0;
                 rest.1.reverse();
-                for i in 0..rest.1.len() {
-                    sum += rest.1[i];
-                    rest.1[i] = sum;
+                for offset in rest.1.iter_mut() {
+                    sum += *offset;
+                    *offset = sum;
                 }
-                pred.push_back(SudiceCode::Select(rest.1));
+                pred.push_back((SudiceCode::Select(rest.1), span(&whole)));
                 pred.append(&mut rest.0);
                 pred
             }
         }
-        _jump_seq(&self) -> (LinkedList<SudiceCode>, Vec<usize>) {
+        // The operands of a chain of powers but the last, first to last,
+        // along with where each `**` between them ends.
+        _bases(&self) -> (Vec<SudiceCodeList>, Vec<usize>) {
+            (_: raise, mut bases: _bases(), op: pow, base: _expr()) => {
+                bases.0.push(base);
+                bases.1.push(op.end);
+                bases
+            },
+            (base: _expr()) => (vec![base], vec![])
+        }
+        // The elements of a tuple, first to last, or the lone expression
+        // within parentheses.
+        _elements(&self) -> Vec<SudiceCodeList> {
+            (_: tuple, mut left: _elements(), _: comma, right: _expr()) => {
+                left.push(right);
+                left
+            },
+            (e: _expr()) => vec![e]
+        }
+        // The last instruction emitted for any subexpression spans all of
+        // it, so Jump instructions borrow the span of the branch they end.
+        _jump_seq(&self) -> (SudiceCodeList, Vec<usize>) {
             (_: ecase, mut end: _expr(), _: selend) => {
-                end.push_back(SudiceCode::Jump(0));
+                let branch = end.back().unwrap().1;
+                end.push_back((SudiceCode::Jump(0), branch));
                 let v = vec![end.len()];
                 (end, v)
             },
             (mut head: _expr(), mut rest: _jump_seq()) => {
                 let offset = rest.0.len();
-                head.push_back(SudiceCode::Jump(offset));
+                let branch = head.back().unwrap().1;
+                head.push_back((SudiceCode::Jump(offset), branch));
                 rest.1.push(head.len());
                 head.append(&mut rest.0);
                 (head, rest.1) 
@@ -155,3 +379,16 @@ impl_rdp! {
         }
     }
 }
+
+// The source text a rule matched, before any operands are joined to it.
+fn span(token: &Token<Rule>) -> SudiceSpan {
+    SudiceSpan::new(token.start, token.end)
+}
+
+// Precedence climbing may end a binary operator's token early when its
+// right operand is itself a chain of tighter-binding operators, so extend
+// it to cover the right operand's span.
+fn join(token: &Token<Rule>, right: &SudiceCodeList) -> SudiceSpan {
+    let end = right.back().map_or(token.end, |&(_, s)| s.end);
+    SudiceSpan::new(token.start, cmp::max(token.end, end))
+}
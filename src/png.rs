@@ -0,0 +1,256 @@
+use std::vec::Vec;
+
+pub type Color = (u8, u8, u8);
+
+// Glyphs three pixels wide and five tall for printable ASCII, one row of
+// three bits to a byte, top row first and the leftmost pixel highest.
+const FONT: [[u8; 5]; 95] = [
+    [0b000, 0b000, 0b000, 0b000, 0b000], // ' '
+    [0b010, 0b010, 0b010, 0b000, 0b010], // !
+    [0b101, 0b101, 0b000, 0b000, 0b000], // "
+    [0b101, 0b111, 0b101, 0b111, 0b101], // #
+    [0b011, 0b110, 0b010, 0b011, 0b110], // $
+    [0b101, 0b001, 0b010, 0b100, 0b101], // %
+    [0b010, 0b101, 0b010, 0b101, 0b011], // &
+    [0b010, 0b010, 0b000, 0b000, 0b000], // '
+    [0b001, 0b010, 0b010, 0b010, 0b001], // (
+    [0b100, 0b010, 0b010, 0b010, 0b100], // )
+    [0b000, 0b101, 0b010, 0b101, 0b000], // *
+    [0b000, 0b010, 0b111, 0b010, 0b000], // +
+    [0b000, 0b000, 0b000, 0b010, 0b100], // ,
+    [0b000, 0b000, 0b111, 0b000, 0b000], // -
+    [0b000, 0b000, 0b000, 0b000, 0b010], // .
+    [0b001, 0b001, 0b010, 0b100, 0b100], // /
+    [0b111, 0b101, 0b101, 0b101, 0b111], // 0
+    [0b010, 0b110, 0b010, 0b010, 0b111], // 1
+    [0b111, 0b001, 0b111, 0b100, 0b111], // 2
+    [0b111, 0b001, 0b111, 0b001, 0b111], // 3
+    [0b101, 0b101, 0b111, 0b001, 0b001], // 4
+    [0b111, 0b100, 0b111, 0b001, 0b111], // 5
+    [0b111, 0b100, 0b111, 0b101, 0b111], // 6
+    [0b111, 0b001, 0b001, 0b010, 0b010], // 7
+    [0b111, 0b101, 0b111, 0b101, 0b111], // 8
+    [0b111, 0b101, 0b111, 0b001, 0b111], // 9
+    [0b000, 0b010, 0b000, 0b010, 0b000], // :
+    [0b000, 0b010, 0b000, 0b010, 0b100], // ;
+    [0b001, 0b010, 0b100, 0b010, 0b001], // <
+    [0b000, 0b111, 0b000, 0b111, 0b000], // =
+    [0b100, 0b010, 0b001, 0b010, 0b100], // >
+    [0b111, 0b001, 0b010, 0b000, 0b010], // ?
+    [0b010, 0b101, 0b111, 0b100, 0b011], // @
+    [0b010, 0b101, 0b111, 0b101, 0b101], // A
+    [0b110, 0b101, 0b110, 0b101, 0b110], // B
+    [0b011, 0b100, 0b100, 0b100, 0b011], // C
+    [0b110, 0b101, 0b101, 0b101, 0b110], // D
+    [0b111, 0b100, 0b110, 0b100, 0b111], // E
+    [0b111, 0b100, 0b110, 0b100, 0b100], // F
+    [0b011, 0b100, 0b101, 0b101, 0b011], // G
+    [0b101, 0b101, 0b111, 0b101, 0b101], // H
+    [0b111, 0b010, 0b010, 0b010, 0b111], // I
+    [0b001, 0b001, 0b001, 0b101, 0b010], // J
+    [0b101, 0b101, 0b110, 0b101, 0b101], // K
+    [0b100, 0b100, 0b100, 0b100, 0b111], // L
+    [0b101, 0b111, 0b111, 0b101, 0b101], // M
+    [0b110, 0b101, 0b101, 0b101, 0b101], // N
+    [0b010, 0b101, 0b101, 0b101, 0b010], // O
+    [0b110, 0b101, 0b110, 0b100, 0b100], // P
+    [0b010, 0b101, 0b101, 0b110, 0b011], // Q
+    [0b110, 0b101, 0b110, 0b101, 0b101], // R
+    [0b011, 0b100, 0b010, 0b001, 0b110], // S
+    [0b111, 0b010, 0b010, 0b010, 0b010], // T
+    [0b101, 0b101, 0b101, 0b101, 0b111], // U
+    [0b101, 0b101, 0b101, 0b101, 0b010], // V
+    [0b101, 0b101, 0b111, 0b111, 0b101], // W
+    [0b101, 0b101, 0b010, 0b101, 0b101], // X
+    [0b101, 0b101, 0b010, 0b010, 0b010], // Y
+    [0b111, 0b001, 0b010, 0b100, 0b111], // Z
+    [0b011, 0b010, 0b010, 0b010, 0b011], // [
+    [0b100, 0b100, 0b010, 0b001, 0b001], // \
+    [0b110, 0b010, 0b010, 0b010, 0b110], // ]
+    [0b010, 0b101, 0b000, 0b000, 0b000], // ^
+    [0b000, 0b000, 0b000, 0b000, 0b111], // _
+    [0b100, 0b010, 0b000, 0b000, 0b000], // `
+    [0b000, 0b011, 0b101, 0b101, 0b011], // a
+    [0b100, 0b110, 0b101, 0b101, 0b110], // b
+    [0b000, 0b011, 0b100, 0b100, 0b011], // c
+    [0b001, 0b011, 0b101, 0b101, 0b011], // d
+    [0b000, 0b010, 0b111, 0b100, 0b011], // e
+    [0b001, 0b010, 0b111, 0b010, 0b010], // f
+    [0b011, 0b101, 0b011, 0b001, 0b110], // g
+    [0b100, 0b110, 0b101, 0b101, 0b101], // h
+    [0b010, 0b000, 0b010, 0b010, 0b010], // i
+    [0b001, 0b000, 0b001, 0b101, 0b010], // j
+    [0b100, 0b101, 0b110, 0b110, 0b101], // k
+    [0b110, 0b010, 0b010, 0b010, 0b011], // l
+    [0b000, 0b111, 0b111, 0b101, 0b101], // m
+    [0b000, 0b110, 0b101, 0b101, 0b101], // n
+    [0b000, 0b010, 0b101, 0b101, 0b010], // o
+    [0b000, 0b110, 0b101, 0b110, 0b100], // p
+    [0b000, 0b011, 0b101, 0b011, 0b001], // q
+    [0b000, 0b011, 0b100, 0b100, 0b100], // r
+    [0b000, 0b011, 0b110, 0b001, 0b110], // s
+    [0b010, 0b111, 0b010, 0b010, 0b001], // t
+    [0b000, 0b101, 0b101, 0b101, 0b011], // u
+    [0b000, 0b101, 0b101, 0b101, 0b010], // v
+    [0b000, 0b101, 0b101, 0b111, 0b111], // w
+    [0b000, 0b101, 0b010, 0b010, 0b101], // x
+    [0b101, 0b101, 0b011, 0b001, 0b110], // y
+    [0b000, 0b111, 0b010, 0b100, 0b111], // z
+    [0b011, 0b010, 0b110, 0b010, 0b011], // {
+    [0b010, 0b010, 0b010, 0b010, 0b010], // |
+    [0b110, 0b010, 0b011, 0b010, 0b110], // }
+    [0b000, 0b001, 0b111, 0b100, 0b000], // ~
+];
+
+// Each pixel of a glyph is drawn as a square this many pixels wide, and
+// glyphs are spaced a pixel apart at that scale.
+pub const TEXT_SCALE: usize = 2;
+pub const CHAR_WIDTH: usize = 4 * TEXT_SCALE;
+pub const CHAR_HEIGHT: usize = 5 * TEXT_SCALE;
+
+// An RGB image to draw charts onto before encoding them.
+pub struct Canvas {
+    pub width: usize,
+    pub height: usize,
+    pixels: Vec<Color>,
+}
+
+impl Canvas {
+    pub fn new(width: usize, height: usize, background: Color) -> Canvas {
+        Canvas { width, height, pixels: vec![background; width * height] }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Color {
+        self.pixels[y * self.width + x]
+    }
+
+    fn plot(&mut self, x: i64, y: i64, color: Color) {
+        if x >= 0 && y >= 0 && (x as usize) < self.width && (y as usize) < self.height {
+            self.pixels[y as usize * self.width + x as usize] = color;
+        }
+    }
+
+    pub fn fill_rect(&mut self, x: f64, y: f64, w: f64, h: f64, color: Color) {
+        let (x0, y0) = (x.round() as i64, y.round() as i64);
+        let (x1, y1) = ((x + w).round() as i64, (y + h).round() as i64);
+        for py in y0..y1 {
+            for px in x0..x1 {
+                self.plot(px, py, color);
+            }
+        }
+    }
+
+    // Bresenham's line, two pixels thick so that it holds up as well as the
+    // bars do.
+    pub fn line(&mut self, x0: f64, y0: f64, x1: f64, y1: f64, color: Color) {
+        let (mut x, mut y) = (x0.round() as i64, y0.round() as i64);
+        let (x1, y1) = (x1.round() as i64, y1.round() as i64);
+        let (dx, dy) = ((x1 - x).abs(), -(y1 - y).abs());
+        let (sx, sy) = (if x < x1 { 1 } else { -1 }, if y < y1 { 1 } else { -1 });
+        let mut err = dx + dy;
+        loop {
+            self.plot(x, y, color);
+            self.plot(x + 1, y, color);
+            self.plot(x, y + 1, color);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+    }
+
+    // Draws `text` with its top left corner at (x, y). Characters outside
+    // printable ASCII are drawn as question marks.
+    pub fn text(&mut self, x: f64, y: f64, text: &str, color: Color) {
+        let (x, y) = (x.round() as i64, y.round() as i64);
+        for (i, c) in text.chars().enumerate() {
+            let glyph = match c {
+                ' '..='~' => &FONT[c as usize - ' ' as usize],
+                _ => &FONT['?' as usize - ' ' as usize],
+            };
+            let left = x + (i * CHAR_WIDTH) as i64;
+            for (row, bits) in glyph.iter().enumerate() {
+                for col in 0..3 {
+                    if bits & (0b100 >> col) != 0 {
+                        for sy in 0..TEXT_SCALE {
+                            for sx in 0..TEXT_SCALE {
+                                let px = left + (col * TEXT_SCALE + sx) as i64;
+                                let py = y + (row * TEXT_SCALE + sy) as i64;
+                                self.plot(px, py, color);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Encodes the canvas as a PNG. The image data is stored uncompressed,
+    // which deflate allows, so no compressor is needed.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut raw = Vec::with_capacity(self.height * (1 + 3 * self.width));
+        for y in 0..self.height {
+            raw.push(0); // No filter.
+            for x in 0..self.width {
+                let (r, g, b) = self.pixel(x, y);
+                raw.extend_from_slice(&[r, g, b]);
+            }
+        }
+
+        let mut zlib = vec![0x78, 0x01];
+        let mut blocks = raw.chunks(0xffff).peekable();
+        if blocks.peek().is_none() {
+            zlib.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+        }
+        while let Some(block) = blocks.next() {
+            let len = block.len() as u16;
+            zlib.push(if blocks.peek().is_none() { 1 } else { 0 });
+            zlib.extend_from_slice(&len.to_le_bytes());
+            zlib.extend_from_slice(&(!len).to_le_bytes());
+            zlib.extend_from_slice(block);
+        }
+        zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&(self.width as u32).to_be_bytes());
+        header.extend_from_slice(&(self.height as u32).to_be_bytes());
+        header.extend_from_slice(&[8, 2, 0, 0, 0]); // 8-bit RGB, not interlaced.
+
+        let mut png = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+        chunk(&mut png, b"IHDR", &header);
+        chunk(&mut png, b"IDAT", &zlib);
+        chunk(&mut png, b"IEND", &[]);
+        png
+    }
+}
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let mut crc = !0u32;
+    for &byte in kind.iter().chain(data) {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { 0xedb8_8320 ^ (crc >> 1) } else { crc >> 1 };
+        }
+    }
+    png.extend_from_slice(&(!crc).to_be_bytes());
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
@@ -0,0 +1,167 @@
+use descriptor::{SudiceCode, SudiceError, SudiceExpression};
+
+use std::vec::Vec;
+
+// Dice rolls produce vectors of faces which are implicitly summed into
+// scalars by any operation which is not specifically about dice. Booleans
+// are scalars of 1 or 2 which may be used as any other scalar, but are set
+// apart so that they can be shown as the chance of being true. A tuple of
+// some number of values can only be the result of a whole expression. The
+// collected runs of an expression can be dropped and capped like dice, but
+// have no die to be rerolled with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SudiceType {
+    Scalar,
+    Vector,
+    Collection,
+    Boolean,
+    Tuple(usize),
+}
+
+pub fn type_check(d: &SudiceExpression) -> Result<SudiceType, SudiceError> {
+    let mut stack = Vec::with_capacity(d.code.len());
+    let mut vars = vec![SudiceType::Scalar; d.slots];
+    type_check_with(d, 0, false, &mut stack, &mut vars)?;
+    Ok(stack.pop().unwrap())
+}
+
+// Walks the bytecode from `start` until the end of the expression or, when
+// checking a select branch, until that branch's jump. Unlike the bounds
+// check, every branch is checked whether or not it is reachable. `vars`
+// holds the type bound to each slot by the lets enclosing the instruction.
+fn type_check_with(d: &SudiceExpression, start: usize, until_jump: bool, stack: &mut Vec<SudiceType>,
+                   vars: &mut [SudiceType]) -> Result<(), SudiceError> {
+    let mut dcp = start;
+    // Nothing can take a tuple apart again, so nothing may pop one.
+    macro_rules! pop {
+        () => {
+            match stack.pop().unwrap() {
+                SudiceType::Tuple(_) => return Err(SudiceError::new(d.spans[dcp], "Attempted to use a tuple as a single value.")),
+                t => t,
+            }
+        }
+    }
+    macro_rules! op2 {
+        ($t:expr) => {{
+            let _ = pop!();
+            let _ = pop!();
+            stack.push($t);
+        }}
+    }
+    macro_rules! dice_op {
+        ($verb:expr, $collection:expr) => {{
+            let t = pop!();
+            let _ = pop!();
+            match t {
+                SudiceType::Vector => {},
+                SudiceType::Collection if $collection => {},
+                SudiceType::Collection => {
+                    let message = format!("Attempted to {} collected results.", $verb);
+                    return Err(SudiceError::new(d.spans[dcp], message));
+                },
+                _ => {
+                    let message = format!("Attempted to {} scalar.", $verb);
+                    return Err(SudiceError::new(d.spans[dcp], message));
+                },
+            }
+            stack.push(t);
+        }}
+    }
+    // Matching faces needs the dice of its top of stack operand, but only
+    // counts them. Some also take a scalar operand below it.
+    macro_rules! pool_op {
+        ($operand:expr) => {{
+            let t = pop!();
+            if $operand {
+                let _ = pop!();
+            }
+            if t != SudiceType::Vector && t != SudiceType::Collection {
+                return Err(SudiceError::new(d.spans[dcp], "Attempted to match faces of scalar."));
+            }
+            stack.push(SudiceType::Scalar);
+        }}
+    }
+    while dcp < d.code.len() {
+        match d.code[dcp] {
+            SudiceCode::Num(_) => stack.push(SudiceType::Scalar),
+            SudiceCode::Bool(_) => stack.push(SudiceType::Boolean),
+            SudiceCode::RollConst(..) => stack.push(SudiceType::Vector),
+            SudiceCode::Add | SudiceCode::Sub | SudiceCode::Mul |
+            SudiceCode::Div | SudiceCode::DivCeil | SudiceCode::DivRound |
+            SudiceCode::Mod | SudiceCode::Pow | SudiceCode::Min | SudiceCode::Max => op2!(SudiceType::Scalar),
+            SudiceCode::Lt | SudiceCode::Gt | SudiceCode::Eq | SudiceCode::Ne |
+            SudiceCode::And | SudiceCode::Or | SudiceCode::Xor => op2!(SudiceType::Boolean),
+            SudiceCode::Roll => op2!(SudiceType::Vector),
+            SudiceCode::Reroll | SudiceCode::RerollLowest | SudiceCode::RerollHighest |
+            SudiceCode::RerollBelow | SudiceCode::RerollAbove | SudiceCode::RerollUntil |
+            SudiceCode::RerollBelowUntil | SudiceCode::RerollAboveUntil => dice_op!("reroll", false),
+            SudiceCode::DropLowest | SudiceCode::DropHighest => dice_op!("drop", true),
+            // Capping a boolean may leave something other than 1 or 2.
+            SudiceCode::Ceil | SudiceCode::Floor => {
+                let t = pop!();
+                let _ = pop!();
+                stack.push(match t {
+                    SudiceType::Vector | SudiceType::Collection => t,
+                    _ => SudiceType::Scalar,
+                });
+            },
+            // A single repetition passes its operand through untouched, but
+            // anything more collapses it, so only a scalar can be relied on.
+            SudiceCode::BestOf(_) | SudiceCode::WorstOf(_) | SudiceCode::SumOf(_) => op2!(SudiceType::Scalar),
+            SudiceCode::CollectOf(_) => op2!(SudiceType::Collection),
+            SudiceCode::Largest | SudiceCode::Pairs | SudiceCode::Longest | SudiceCode::Unique => pool_op!(false),
+            SudiceCode::Highest => pool_op!(true),
+            SudiceCode::Select(ref offsets) => {
+                let _ = pop!();
+                let mut branch = dcp + 1;
+                let mut boolean = true;
+                for offset in offsets {
+                    type_check_with(d, branch, true, stack, vars)?;
+                    boolean &= pop!() == SudiceType::Boolean;
+                    branch = dcp + offset + 1;
+                }
+                stack.push(if boolean { SudiceType::Boolean } else { SudiceType::Scalar });
+                dcp += offsets[offsets.len()-1];
+            },
+            SudiceCode::Label(_) => {},
+            // A let binds the sum of any dice, as a name stands for a single
+            // value however many times it is used.
+            SudiceCode::Bind(slot, _) => vars[slot] = match pop!() {
+                SudiceType::Vector | SudiceType::Collection => SudiceType::Scalar,
+                t => t,
+            },
+            SudiceCode::Load(slot) => stack.push(vars[slot]),
+            SudiceCode::Tuple(n) => {
+                for _ in 0..n {
+                    let _ = pop!();
+                }
+                stack.push(SudiceType::Tuple(n));
+            },
+            // Conditioning anything less than the whole expression would
+            // discard the rest of the sample along with it.
+            SudiceCode::Given => {
+                if dcp + 1 != d.code.len() {
+                    return Err(SudiceError::new(d.spans[dcp], "Only a whole expression can be given a condition."));
+                }
+                let t = stack.pop().unwrap();
+                let _ = pop!();
+                stack.push(t);
+            },
+            SudiceCode::Jump(_) => if until_jump {
+                return Ok(());
+            } else {
+                panic!("Error: Illegal bytecode sequence: Should not reach jump!");
+            },
+            SudiceCode::Not => {
+                let _ = pop!();
+                stack.push(SudiceType::Boolean);
+            },
+            SudiceCode::Count | SudiceCode::Abs | SudiceCode::Neg => {
+                let _ = pop!();
+                stack.push(SudiceType::Scalar);
+            },
+        }
+        dcp += 1;
+    }
+    Ok(())
+}